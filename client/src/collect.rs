@@ -1,4 +1,5 @@
 use measure::MeasureResponse;
+use std::ops::Add;
 
 pub fn average<'a, I: Iterator<Item = &'a MeasureResponse>>(
     items: I,
@@ -12,6 +13,13 @@ pub fn average<'a, I: Iterator<Item = &'a MeasureResponse>>(
         tls_handshake_duration: Some(Default::default()),
         ip: String::new(),
         overall_duration: Some(Default::default()),
+        ttlb_duration: Some(Default::default()),
+        response_size: Some(0),
+        decoded_size: Some(0),
+        bytes_received: Some(0),
+        average_throughput: Some(0.0),
+        peak_throughput: Some(0.0),
+        ..Default::default()
     };
 
     let mut summed = items.fold(starting, |mut init, val| {
@@ -41,6 +49,13 @@ pub fn average<'a, I: Iterator<Item = &'a MeasureResponse>>(
             init.overall_duration = None;
         }
 
+        init.ttlb_duration = add(init.ttlb_duration, val.ttlb_duration);
+        init.response_size = add(init.response_size, val.response_size);
+        init.decoded_size = add(init.decoded_size, val.decoded_size);
+        init.bytes_received = add(init.bytes_received, val.bytes_received);
+        init.average_throughput = add(init.average_throughput, val.average_throughput);
+        init.peak_throughput = add(init.peak_throughput, val.peak_throughput);
+
         init.tcp_connect_duration += val.tcp_connect_duration;
         init.http_get_send_duration += val.http_get_send_duration;
         init.ttfb_duration += val.ttfb_duration;
@@ -60,9 +75,21 @@ pub fn average<'a, I: Iterator<Item = &'a MeasureResponse>>(
         summed.overall_duration = Some(dur / times as u32);
     }
 
+    summed.ttlb_duration = summed.ttlb_duration.map(|dur| dur / times as u32);
+    summed.response_size = summed.response_size.map(|size| size / times as u64);
+    summed.decoded_size = summed.decoded_size.map(|size| size / times as u64);
+    summed.bytes_received = summed.bytes_received.map(|size| size / times as u64);
+    summed.average_throughput = summed.average_throughput.map(|rate| rate / times as f64);
+    summed.peak_throughput = summed.peak_throughput.map(|rate| rate / times as f64);
+
     summed.tcp_connect_duration /= times as u32;
     summed.http_get_send_duration /= times as u32;
     summed.ttfb_duration /= times as u32;
 
     summed
 }
+
+/// Sum two values, if either is None the sum will be None
+fn add<T: Add<Output = T>>(sum: Option<T>, value: Option<T>) -> Option<T> {
+    Some(sum? + value?)
+}
@@ -1,7 +1,11 @@
-use crate::CliArgs;
+use crate::{BodyEncoding, CliArgs};
 use anyhow::Context;
+use measure::{
+    AddressFamily, DnsResolver, HttpMethod, HttpProtocol, RedirectPolicy, RequestBody, Verify,
+    Warmup,
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, net::IpAddr};
 
 /// The Jobs struct is used to store the parsed jobs from the config file
 /// on creation it tries to read from the outputs.json file created by the aws deployment,
@@ -13,13 +17,39 @@ pub struct Jobs {
     // The parsed url of the target request
     pub target_url: String,
     // The parsed method of the target request
-    pub target_method: String,
+    pub target_method: HttpMethod,
+    // The http version of the target request
+    pub target_protocol: HttpProtocol,
+    // The redirects from the target that are followed
+    pub redirect: RedirectPolicy,
+    // The addresses hosts are connected to instead of being resolved
+    pub resolve: HashMap<String, IpAddr>,
+    // Whether every address of the target's host is measured
+    pub fan_out: bool,
+    // The resolver hosts are looked up with
+    pub resolver: DnsResolver,
+    // The addresses of the target that are connected to
+    pub address_family: AddressFamily,
+    // Whether the target is measured over ipv4 and over ipv6
+    pub dual_stack: bool,
     // The parsed body of the target request
-    pub target_body: Option<String>,
+    pub target_body: Option<RequestBody>,
     // The parsed headers of the target request
     pub target_headers: Option<HashMap<String, String>>,
     // The parsed url of the comparison request
     pub comparison_url: Option<String>,
+    // The warmup the measure service does before each target request
+    pub warmup: Warmup,
+    // The requests made over each measured request's connection after it
+    pub warm: usize,
+    // Whether each request is measured again over a connection resuming its tls session
+    pub resume: bool,
+    // Whether a non success status from the target is recorded as an error
+    pub fail_on_error_status: bool,
+    // The response headers the measure service records
+    pub capture_headers: Vec<String>,
+    // What the response body is checked against
+    pub verify: Option<Verify>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,17 +61,67 @@ struct Inner {
 impl CliArgs {
     pub fn jobs(&self) -> anyhow::Result<Jobs> {
         Ok(Jobs {
-            services: self.services.clone().unwrap_or(try_read_service_ips()?),
-            target_method: self.target_request_method.clone().unwrap_or("GET".to_string()),
-            target_body: self.target_request_body.clone(),
+            services: match self.services {
+                Some(ref services) => services.clone(),
+                None => try_read_service_ips()?,
+            },
+            target_method: self.target_request_method.unwrap_or(HttpMethod::Get),
+            target_protocol: self.target_request_protocol,
+            redirect: match self.follow_redirects {
+                Some(max) => RedirectPolicy::Follow { max },
+                None => RedirectPolicy::None,
+            },
+            resolve: self.resolve.iter().cloned().collect(),
+            fan_out: self.fan_out,
+            resolver: self.dns_resolver.clone(),
+            address_family: self.address_family,
+            dual_stack: self.dual_stack,
+            target_body: self.target_body()?,
             target_headers: self.target_request_headers.clone().map(|v| v.into_iter().collect()),
             comparison_url: self.comparison_url.clone(),
-            target_url: self
-                .target_request_url
-                .clone()
-                .unwrap_or(try_get_deployed_url()?),
+            warmup: self.warmup(),
+            warm: self.warm,
+            resume: self.resume,
+            fail_on_error_status: self.fail_on_error_status,
+            capture_headers: self.capture_headers.clone(),
+            verify: self.verify.clone(),
+            target_url: match self.target_request_url {
+                Some(ref url) => url.clone(),
+                None => try_get_deployed_url()?,
+            },
         })
     }
+
+    fn target_body(&self) -> anyhow::Result<Option<RequestBody>> {
+        let Some(ref body) = self.target_request_body else {
+            return Ok(None);
+        };
+
+        Ok(Some(match self.target_request_body_encoding {
+            BodyEncoding::Json => RequestBody::Json(
+                serde_json::from_str(body).context("the target request body is not valid json")?,
+            ),
+            BodyEncoding::Text => RequestBody::Text(body.clone()),
+            BodyEncoding::Base64 => RequestBody::Base64(body.clone()),
+            BodyEncoding::Form => RequestBody::Form(
+                body.split('&')
+                    .filter(|field| !field.is_empty())
+                    .map(|field| match field.split_once('=') {
+                        Some((key, value)) => (key.to_string(), value.to_string()),
+                        None => (field.to_string(), String::new()),
+                    })
+                    .collect(),
+            ),
+        }))
+    }
+
+    fn warmup(&self) -> Warmup {
+        match (&self.warmup_url, self.warmup) {
+            (Some(url), _) => Warmup::Url { url: url.clone() },
+            (None, Some(count)) => Warmup::Target { count },
+            (None, None) => Warmup::None,
+        }
+    }
 }
 
 pub fn try_read_service_ips() -> anyhow::Result<Vec<String>> {
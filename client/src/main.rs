@@ -1,14 +1,29 @@
 mod collect;
 mod jobs;
+mod stream;
 
-use std::{collections::HashMap, error::Error, fmt::Write};
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
 
-use clap::Parser;
-use indicatif::{ProgressState, ProgressStyle};
+use clap::{Parser, ValueEnum};
+use futures::{Stream, StreamExt};
+use indicatif::ProgressStyle;
 use jobs::Jobs;
-use measure::{MeasureDurationRequest, MeasureRequest, MeasureResponse};
-use reqwest::{ClientBuilder, RequestBuilder};
+use measure::{
+    auth::Credentials, AddressFamily, DnsResolver, ErrorKind, ErrorResponse, HealthResponse,
+    HttpMethod, HttpProtocol, MeasureBatchRequest, MeasureDurationRequest, MeasureRequest,
+    MeasureResponse, ServiceInfo, Verify, Warmup,
+};
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    ClientBuilder, Method, RequestBuilder, Response, StatusCode,
+};
 use serde::{Deserialize, Serialize};
+use stream::SampleEvent;
 use tabled::builder::Builder;
 
 #[derive(Parser)]
@@ -18,17 +33,84 @@ pub struct CliArgs {
 
     /// The HTTP method for the http request the measure service will be making to the target url
     #[clap(long)]
-    target_request_method: Option<String>,
+    target_request_method: Option<HttpMethod>,
+
+    /// The HTTP version the measure service makes the request to the target with, `h1`, `h2` or `h3`
+    #[clap(long, default_value = "h1")]
+    target_request_protocol: HttpProtocol,
 
     /// The HTTP body for the http request the measure service will be making to the target url
     #[clap(long)]
     target_request_body: Option<String>,
 
+    /// How the HTTP body is encoded, form bodies are given as `key=value&key2=value2`
+    #[clap(long, value_enum, default_value_t = BodyEncoding::Json)]
+    target_request_body_encoding: BodyEncoding,
+
     /// The HTTP headers for the http request the measure service will be making to the target url
     #[arg(value_parser = parse_key_val::<String, String>)]
     #[clap(long)]
     target_request_headers: Option<Vec<(String, String)>>,
 
+    /// Follow up to this many redirects from the target, measuring every request of the chain
+    #[clap(long)]
+    follow_redirects: Option<usize>,
+
+    /// Connect to this address for the host instead of resolving it, as `host=ip`, can be repeated
+    #[arg(value_parser = parse_key_val::<String, IpAddr>)]
+    #[clap(long)]
+    resolve: Vec<(String, IpAddr)>,
+
+    /// Measure every address the target's host resolves to, each on its own
+    #[clap(long)]
+    fan_out: bool,
+
+    /// The resolver the measure service looks hosts up with, `system`, `udp:<server>`,
+    /// `tcp:<server>`, `tls:<name>@<server>` or `https:<name>@<server>[/path]`
+    #[arg(value_parser = parse_resolver)]
+    #[clap(long, default_value = "system")]
+    dns_resolver: DnsResolver,
+
+    /// The addresses of the target the measure service connects to, `any` for the first one,
+    /// `v4`, `v6`, or `both` to race them with happy eyeballs
+    #[clap(long, default_value = "any")]
+    address_family: AddressFamily,
+
+    /// Measure over ipv4 and over ipv6, comparing the two
+    #[clap(long)]
+    dual_stack: bool,
+
+    /// The number of warmup requests the measure service sends to the target before each measurement
+    #[clap(long)]
+    warmup: Option<usize>,
+
+    /// A url the measure service sends a warmup request to before each measurement,
+    /// takes precedence over `--warmup`
+    #[clap(long)]
+    warmup_url: Option<String>,
+
+    /// The number of requests the measure service makes over each measured request's connection
+    /// once it's done, to compare warm requests to cold ones
+    #[clap(long, default_value_t = 0)]
+    warm: usize,
+
+    /// Also measure each request over a new connection resuming the tls session of the first
+    #[clap(long)]
+    resume: bool,
+
+    /// Record a non success status from the target as an error rather than a measurement
+    #[clap(long)]
+    fail_on_error_status: bool,
+
+    /// A response header the measure service records with each measurement, can be repeated
+    #[clap(long = "capture-header")]
+    capture_headers: Vec<String>,
+
+    /// Check the response body, `cid` for the cid in the url, `cid:<cid>` or `sha256:<hex digest>`
+    #[arg(value_parser = parse_verify)]
+    #[clap(long)]
+    verify: Option<Verify>,
+
     /// The comparison url the measure service will be calling the http `get` method` on
     #[clap(long = "comp")]
     comparison_url: Option<String>,
@@ -49,16 +131,62 @@ pub struct CliArgs {
     #[clap(short, long, default_value_t = 500)]
     delay: usize,
 
+    /// A random extra delay of up to this many milliseconds added to each delay
+    #[clap(short, long)]
+    jitter: Option<u64>,
+
     /// The output file to write the json results to
     #[clap(short, long)]
     output_dir: Option<String>,
 
+    /// Accept invalid tls certificates from the measure services, for services using a self signed certificate
+    #[clap(long)]
+    insecure: bool,
+
+    /// The bearer token to authenticate with the measure services
+    #[clap(long, env = "MEASURE_AUTH_TOKEN", conflicts_with = "auth_key_id")]
+    auth_token: Option<String>,
+
+    /// The key id to sign requests to the measure services with, instead of sending a token
+    #[clap(long, env = "MEASURE_AUTH_KEY_ID", requires = "auth_secret")]
+    auth_key_id: Option<String>,
+
+    /// The secret of the key to sign requests to the measure services with
+    #[clap(long, env = "MEASURE_AUTH_SECRET", requires = "auth_key_id")]
+    auth_secret: Option<String>,
+
+    /// Stream the samples from every service at once and update the tables as they arrive
+    #[clap(long)]
+    live: bool,
+
     /// Creates requests concurrently rather than sequentially
     /// and ignores the delay param
     #[clap(long)]
     flood: bool,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum BodyEncoding {
+    Json,
+    Text,
+    Base64,
+    Form,
+}
+
+impl CliArgs {
+    /// The credentials to authenticate with the measure services, signing takes precedence
+    fn credentials(&self) -> Option<Credentials> {
+        match (&self.auth_key_id, &self.auth_secret, &self.auth_token) {
+            (Some(key_id), Some(secret), _) => Some(Credentials::Hmac {
+                key_id: key_id.clone(),
+                secret: secret.clone(),
+            }),
+            (_, _, Some(token)) => Some(Credentials::Bearer(token.clone())),
+            _ => None,
+        }
+    }
+}
+
 /// Parse a single key-value pair
 fn parse_key_val<T, U>(s: &str) -> Result<(T, U), Box<dyn Error + Send + Sync + 'static>>
 where
@@ -73,11 +201,63 @@ where
     Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
 }
 
+/// Parse what the response body is checked against
+fn parse_verify(s: &str) -> Result<Verify, String> {
+    match s.split_once(':') {
+        None if s == "cid" => Ok(Verify::Cid { cid: None }),
+        Some(("cid", cid)) => Ok(Verify::Cid {
+            cid: Some(cid.to_string()),
+        }),
+        Some(("sha256", digest)) => Ok(Verify::Sha256 {
+            digest: digest.to_string(),
+        }),
+        _ => Err(format!(
+            "expected `cid`, `cid:<cid>` or `sha256:<digest>`, got `{s}`"
+        )),
+    }
+}
+
+/// Parse the resolver the hosts are looked up with
+fn parse_resolver(s: &str) -> Result<DnsResolver, String> {
+    let named = |kind: &str, spec: &str| match spec.split_once('@') {
+        Some((name, server)) if !name.is_empty() && !server.is_empty() => {
+            Ok((name.to_string(), server.to_string()))
+        }
+        _ => Err(format!("expected `{kind}:<name>@<server>`, got `{s}`")),
+    };
+
+    match s.split_once(':') {
+        None if s == "system" => Ok(DnsResolver::System),
+        Some(("udp", server)) => Ok(DnsResolver::Udp {
+            server: server.to_string(),
+        }),
+        Some(("tcp", server)) => Ok(DnsResolver::Tcp {
+            server: server.to_string(),
+        }),
+        Some(("tls", spec)) => {
+            let (name, server) = named("tls", spec)?;
+            Ok(DnsResolver::Tls { server, name })
+        }
+        Some(("https", spec)) => {
+            let (name, server) = named("https", spec)?;
+            let (server, path) = match server.find('/') {
+                Some(i) => (server[..i].to_string(), Some(server[i..].to_string())),
+                None => (server, None),
+            };
+
+            Ok(DnsResolver::Https { server, name, path })
+        }
+        _ => Err(format!(
+            "expected `system`, `udp:<server>`, `tcp:<server>`, `tls:<name>@<server>` or `https:<name>@<server>`, got `{s}`"
+        )),
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = CliArgs::parse();
 
-    let _ = Runtime::new(args)?.start().await?;
+    Runtime::new(args)?.start().await?;
 
     Ok(())
 }
@@ -87,10 +267,16 @@ struct Runtime {
     jobs: Jobs,
     results: HashMap<String, Vec<MeasureResponse>>,
     comparison_results: Option<HashMap<String, Vec<MeasureResponse>>>,
+    errors: HashMap<String, Vec<ErrorResponse>>,
+    services: HashMap<String, ServiceInfo>,
     output_dir: Option<String>,
     average: bool,
+    live: bool,
     times: usize,
     delay: usize,
+    jitter: Option<u64>,
+    insecure: bool,
+    credentials: Option<Credentials>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -99,77 +285,81 @@ struct Output {
     target_results: HashMap<String, Vec<MeasureResponse>>,
     /// mapping from service ip to the results of the comparison url
     comparison_results: Option<HashMap<String, Vec<MeasureResponse>>>,
+    /// mapping from service ip to the errors the service responded with
+    errors: HashMap<String, Vec<ErrorResponse>>,
+    /// mapping from service ip to what the service reported about itself
+    services: HashMap<String, ServiceInfo>,
 }
 
 impl Runtime {
     fn new(args: CliArgs) -> anyhow::Result<Self> {
         Ok(Runtime {
             jobs: args.jobs()?,
+            credentials: args.credentials(),
             results: HashMap::new(),
             comparison_results: args.comparison_url.map(|_| HashMap::new()),
+            errors: HashMap::new(),
+            services: HashMap::new(),
             average: args.average,
+            live: args.live,
             times: args.times,
             delay: args.delay,
+            jitter: args.jitter,
+            insecure: args.insecure,
             output_dir: args.output_dir,
         })
     }
 
     async fn start(mut self) -> anyhow::Result<()> {
-        let Jobs {
-            services,
-            target_url,
-            target_method,
-            target_body,
-            target_headers,
-            comparison_url,
-        } = self.jobs.clone();
+        let jobs = self.jobs.clone();
 
-        for service_ip in services {
-            println!("running for: {}", service_ip);
-            self.run(
-                service_ip,
-                target_url.clone(),
-                target_method.clone(),
-                target_body.clone(),
-                target_headers.clone(),
-                comparison_url.clone(),
-            )
-            .await?;
+        if jobs.target_body.is_some()
+            && matches!(jobs.target_method, HttpMethod::Get | HttpMethod::Head)
+        {
+            return Err(anyhow::anyhow!(
+                "body is not supported for {} requests",
+                jobs.target_method
+            ));
         }
 
-        let output = self.output();
+        let mut services = Vec::with_capacity(jobs.services.len());
 
-        for (ip, results) in output.target_results.iter() {
-            let mut builder = Builder::default();
-            // Push the header row (0..self.times)
-            builder.push_record(
-                std::iter::once(String::from(""))
-                    .chain((0..self.times).map(|i| (i + 1).to_string())),
-            );
+        for service_ip in jobs.services.iter() {
+            if let Err(e) = self.health(service_ip).await {
+                println!("skipping service {}, it is not healthy: {}", service_ip, e);
+                continue;
+            }
 
-            // Push the target url and the results
-            builder.push_record(
-                std::iter::once(target_url.clone()).chain(
-                    results
-                        .iter()
-                        .map(|res| format!("{}ms", res.overall_duration.unwrap().as_millis())),
-                ),
-            );
+            match self.info(service_ip).await {
+                Ok(info) => {
+                    println!(
+                        "service {} is version {} in {}",
+                        service_ip,
+                        info.version,
+                        info.region.as_deref().unwrap_or("an unknown region")
+                    );
+                    self.services.insert(service_ip.clone(), info);
+                }
+                Err(e) => println!("failed to get info from service {}: {}", service_ip, e),
+            }
 
-            // Push the comparison url and the results if applicable
-            if let Some(ref comp) = output.comparison_results {
-                let comp = comp.get(ip).expect("comparison results for this ip");
-                builder.push_record(
-                    std::iter::once(comparison_url.as_ref().expect("comparison url").clone())
-                        .chain(
-                            comp.iter()
-                                .map(|res| format!("{}ms", res.overall_duration.unwrap().as_millis())),
-                        ),
-                );
+            services.push(service_ip.clone());
+        }
+
+        if self.live {
+            self.live(&services, &jobs).await?;
+        } else {
+            for service_ip in services {
+                println!("running for: {}", service_ip);
+                self.run(service_ip, &jobs).await?;
             }
+        }
+
+        let output = self.output();
 
+        for ip in output.target_results.keys() {
             println!("Results for service ip: {}", ip);
-            println!("{}", builder.build());
+            println!("{}", self.table(ip, &jobs));
         }
 
         if let Some(ref dir) = self.output_dir {
@@ -185,145 +375,692 @@ impl Runtime {
         Ok(())
     }
 
-    async fn run(
-        &mut self,
-        service_ip: String,
-        target_url: String,
-        target_method: String,
-        target_body: Option<String>,
-        target_headers: Option<HashMap<String, String>>,
-        maybe_comp: Option<String>,
-    ) -> anyhow::Result<()> {
-        if target_body.is_some() && target_method != "POST" {
-            return Err(anyhow::anyhow!("body is only supported for POST requests"));
+    /// The table of the results of a service, a row for the target url and the comparison url
+    fn table(&self, service_ip: &str, jobs: &Jobs) -> String {
+        let mut builder = Builder::default();
+        // Push the header row (0..self.times)
+        builder.push_record(
+            std::iter::once(String::from("")).chain((0..self.times).map(|i| (i + 1).to_string())),
+        );
+
+        // Push the target url and the results
+        if let Some(results) = self.results.get(service_ip) {
+            builder.push_record(
+                std::iter::once(jobs.target_url.clone()).chain(results.iter().map(format_result)),
+            );
         }
 
-        let req = make_request(
-            &service_ip,
-            &target_url,
-            &target_method,
-            &target_headers,
-            &target_body,
-        )?;
+        // Push the comparison url and the results if applicable
+        if let Some(comp) = self
+            .comparison_results
+            .as_ref()
+            .and_then(|comp| comp.get(service_ip))
+        {
+            builder.push_record(
+                std::iter::once(jobs.comparison_url.clone().expect("comparison url"))
+                    .chain(comp.iter().map(format_result)),
+            );
+        }
 
-        println!("measuring target ttfb");
-        self.results.insert(
-            service_ip.clone(),
-            Self::measure(req, self.times, self.delay).await?,
-        );
+        builder.build().to_string()
+    }
 
-        if let Some(ref url) = maybe_comp {
-            let comparison_req = make_request(
-                &service_ip,
-                &url,
-                &target_method,
-                &target_headers,
-                &target_body,
-            )?;
+    /// Stream the samples from every service at once, redrawing the tables as they arrive
+    async fn live(&mut self, services: &[String], jobs: &Jobs) -> anyhow::Result<()> {
+        let mut streams = Vec::new();
 
-            println!("measuring comparison ttfb");
-            self.comparison_results
-                .as_mut()
-                .expect("comparison results")
-                .insert(
-                    service_ip.clone(),
-                    Self::measure(comparison_req, self.times, self.delay).await?,
-                );
+        for service_ip in services {
+            let mut urls = vec![(&jobs.target_url, false)];
+            if let Some(ref url) = jobs.comparison_url {
+                urls.push((url, true));
+            }
+
+            for (url, comparison) in urls {
+                match self.stream(service_ip, make_request(url, jobs)).await? {
+                    Ok(events) => {
+                        let service_ip = service_ip.clone();
+                        streams.push(
+                            events
+                                .map(move |event| (service_ip.clone(), comparison, event))
+                                .boxed(),
+                        );
+                    }
+                    Err(e) => self.record_error(service_ip, e),
+                }
+            }
         }
 
-        if self.average {
-            let target = collect::average(
-                self.results
-                    .get(&service_ip)
-                    .expect("results for this ip")
-                    .iter(),
-                self.times,
-            );
+        let pb = indicatif::ProgressBar::new_spinner();
+        pb.set_style(ProgressStyle::with_template("{msg}").unwrap());
 
-            print_average(target_url, target);
+        let mut events = futures::stream::select_all(streams);
 
-            match self.comparison_results {
-                Some(ref comp) => {
-                    let comp = collect::average(
-                        comp.get(&service_ip).expect("results for this ip").iter(),
-                        self.times,
-                    );
+        while let Some((service_ip, comparison, event)) = events.next().await {
+            match event {
+                Ok(SampleEvent::Sample(sample)) => {
+                    let results = match comparison {
+                        true => self
+                            .comparison_results
+                            .as_mut()
+                            .expect("comparison results"),
+                        false => &mut self.results,
+                    };
 
-                    print_average(maybe_comp.expect("comparison url"), comp);
+                    results.entry(service_ip).or_default().push(sample);
                 }
-                None => (),
-            };
+                Ok(SampleEvent::Error(e)) => pb.suspend(|| self.record_error(&service_ip, e)),
+                Ok(SampleEvent::Done) => {}
+                Err(e) => {
+                    pb.suspend(|| println!("stream from service {} failed: {}", service_ip, e))
+                }
+            }
+
+            let tables = services
+                .iter()
+                .map(|ip| format!("Service ip: {}\n{}", ip, self.table(ip, jobs)))
+                .collect::<Vec<_>>();
+            pb.set_message(tables.join("\n"));
+        }
+
+        pb.finish_and_clear();
+
+        for service_ip in services {
+            self.print_averages(service_ip, jobs);
         }
 
         Ok(())
     }
 
+    async fn run(&mut self, service_ip: String, jobs: &Jobs) -> anyhow::Result<()> {
+        let Jobs {
+            target_url,
+            comparison_url: maybe_comp,
+            ..
+        } = jobs;
+
+        println!("measuring target ttfb");
+        match self
+            .measure(&service_ip, make_request(target_url, jobs))
+            .await?
+        {
+            Ok(samples) => {
+                self.results.insert(service_ip.clone(), samples);
+            }
+            Err(e) => self.record_error(&service_ip, e),
+        }
+
+        if let Some(ref url) = maybe_comp {
+            println!("measuring comparison ttfb");
+            match self.measure(&service_ip, make_request(url, jobs)).await? {
+                Ok(samples) => {
+                    self.comparison_results
+                        .as_mut()
+                        .expect("comparison results")
+                        .insert(service_ip.clone(), samples);
+                }
+                Err(e) => self.record_error(&service_ip, e),
+            }
+        }
+
+        self.print_averages(&service_ip, jobs);
+
+        Ok(())
+    }
+
+    fn print_averages(&self, service_ip: &str, jobs: &Jobs) {
+        if !self.average {
+            return;
+        }
+
+        if let Some(results) = self.results.get(service_ip) {
+            let target = collect::average(results.iter(), results.len());
+
+            print_average(jobs.target_url.clone(), target);
+            print_verified(results);
+            print_redirects(results);
+            print_addresses(results);
+            print_dual_stack(results);
+            print_tls(results);
+            print_reuse(results);
+        }
+
+        if let Some(comp) = self
+            .comparison_results
+            .as_ref()
+            .and_then(|comp| comp.get(service_ip))
+        {
+            let average = collect::average(comp.iter(), comp.len());
+
+            print_average(
+                jobs.comparison_url.clone().expect("comparison url"),
+                average,
+            );
+            print_verified(comp);
+            print_redirects(comp);
+            print_addresses(comp);
+            print_dual_stack(comp);
+            print_tls(comp);
+            print_reuse(comp);
+        }
+    }
+
+    fn record_error(&mut self, service_ip: &str, error: ErrorResponse) {
+        println!("service {} failed to measure: {}", service_ip, error);
+
+        self.errors
+            .entry(service_ip.to_string())
+            .or_default()
+            .push(error);
+    }
+
+    /// Take all the samples in one batch, the service paces them itself,
+    /// errors from the service are returned separately so they can be recorded
     async fn measure(
-        req: reqwest::RequestBuilder,
-        times: usize,
-        delay: usize,
-    ) -> anyhow::Result<Vec<MeasureResponse>> {
-        let mut buf = Vec::with_capacity(times);
-        let pb = indicatif::ProgressBar::new(times as u64);
+        &self,
+        service_ip: &str,
+        request: MeasureDurationRequest,
+    ) -> anyhow::Result<Result<Vec<MeasureResponse>, ErrorResponse>> {
+        let pb = indicatif::ProgressBar::new_spinner();
+
+        pb.set_style(
+            ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {msg}").unwrap(),
+        );
+        pb.set_message(format!("taking {} samples", self.times));
+        pb.enable_steady_tick(Duration::from_millis(100));
+
+        let batch = MeasureBatchRequest {
+            request,
+            times: self.times,
+            delay: self.delay as u64,
+            jitter: self.jitter,
+        };
 
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-            .progress_chars("#>-"));
+        let res = self
+            .request(
+                Method::POST,
+                service_ip,
+                "/batch",
+                serde_json::to_vec(&batch)?,
+            )?
+            .header(CONTENT_TYPE, "application/json")
+            .send()
+            .await?;
 
-        for i in 0..times {
-            let cloned = req
-                .try_clone()
-                .ok_or(anyhow::anyhow!("failed to clone request"))?;
+        let res = if res.status().is_success() {
+            Ok(res.json::<Vec<MeasureResponse>>().await?)
+        } else {
+            Err(error_response(res).await?)
+        };
 
-            let res = cloned.send().await?.json::<MeasureResponse>().await?;
+        pb.finish_and_clear();
 
-            buf.push(res);
+        Ok(res)
+    }
 
-            pb.set_position(i as u64);
+    /// Start streaming the samples of a batch from the service
+    async fn stream(
+        &self,
+        service_ip: &str,
+        request: MeasureDurationRequest,
+    ) -> anyhow::Result<
+        Result<impl Stream<Item = anyhow::Result<SampleEvent>> + Send + 'static, ErrorResponse>,
+    > {
+        let batch = MeasureBatchRequest {
+            request,
+            times: self.times,
+            delay: self.delay as u64,
+            jitter: self.jitter,
+        };
 
-            tokio::time::sleep(tokio::time::Duration::from_millis(delay as u64)).await;
+        let res = self
+            .request(
+                Method::POST,
+                service_ip,
+                "/stream",
+                serde_json::to_vec(&batch)?,
+            )?
+            .header(CONTENT_TYPE, "application/json")
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            Ok(Ok(stream::events(res)))
+        } else {
+            Ok(Err(error_response(res).await?))
         }
+    }
+
+    async fn health(&self, service_ip: &str) -> anyhow::Result<HealthResponse> {
+        Ok(self
+            .request(Method::GET, service_ip, "/health", Vec::new())?
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
 
-        Ok(buf)
+    async fn info(&self, service_ip: &str) -> anyhow::Result<ServiceInfo> {
+        Ok(self
+            .request(Method::GET, service_ip, "/info", Vec::new())?
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// A request to a route of the service, authenticated with the credentials if there are any
+    fn request(
+        &self,
+        method: Method,
+        service_ip: &str,
+        path: &str,
+        body: Vec<u8>,
+    ) -> anyhow::Result<RequestBuilder> {
+        let mut req = ClientBuilder::new()
+            .danger_accept_invalid_certs(self.insecure)
+            .build()?
+            .request(
+                method.clone(),
+                format!("{}{}", service_ip.trim_end_matches('/'), path),
+            );
+
+        if let Some(ref credentials) = self.credentials {
+            req = req.header(
+                AUTHORIZATION,
+                credentials.authorization(method.as_str(), path, &body),
+            );
+        }
+
+        Ok(req.body(body))
     }
 
     fn output(&self) -> Output {
         Output {
             target_results: self.results.clone(),
             comparison_results: self.comparison_results.clone(),
+            errors: self.errors.clone(),
+            services: self.services.clone(),
         }
     }
 }
 
-fn make_request(
-    service_ip: &String,
-    target_url: &String,
-    target_method: &String,
-    target_headers: &Option<HashMap<String, String>>,
-    target_body: &Option<String>,
-) -> Result<RequestBuilder, reqwest::Error> {
-    let req = ClientBuilder::new().build()?;
-    let req = if target_method != "GET" {
-        req.post(format!("{0}/duration", &service_ip))
-            .json(&MeasureDurationRequest {
-                target: target_url.clone(),
-                method: target_method.clone(),
-                headers: target_headers.clone(),
-                body: target_body.clone(),
-            })
-    } else {
-        req.post(format!("{0}/ttfb", &service_ip))
-            .json(&MeasureRequest {
-                target: target_url.clone(),
-            })
+/// The error a service responded with, one that isn't json, like a plain text 404 from the
+/// router or a proxy's error page, is made from its status and body
+async fn error_response(res: Response) -> anyhow::Result<ErrorResponse> {
+    let status = res.status();
+    let text = res.text().await?;
+
+    if let Ok(error) = serde_json::from_str(&text) {
+        return Ok(error);
+    }
+
+    let kind = match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Unauthorized,
+        StatusCode::NOT_FOUND => ErrorKind::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => ErrorKind::TooManyRequests,
+        status if status.is_client_error() => ErrorKind::BadRequest,
+        _ => ErrorKind::Internal,
+    };
+
+    Ok(ErrorResponse {
+        kind,
+        message: format!("{} {}", status, text.trim()),
+        phase: None,
+        partial: None,
+    })
+}
+
+fn make_request(target_url: &str, jobs: &Jobs) -> MeasureDurationRequest {
+    MeasureDurationRequest {
+        request: MeasureRequest {
+            target: target_url.to_string(),
+            method: jobs.target_method,
+            protocol: jobs.target_protocol,
+            redirect: jobs.redirect,
+            resolve: jobs.resolve.clone(),
+            fan_out: jobs.fan_out,
+            resolver: jobs.resolver.clone(),
+            address_family: jobs.address_family,
+            dual_stack: jobs.dual_stack,
+            headers: jobs.target_headers.clone(),
+            body: jobs.target_body.clone(),
+            capture_headers: jobs.capture_headers.clone(),
+            verify: jobs.verify.clone(),
+        },
+        warmup: jobs.warmup.clone(),
+        include_warmup: !matches!(jobs.warmup, Warmup::None),
+        warm: jobs.warm,
+        resume: jobs.resume,
+        fail_on_error_status: jobs.fail_on_error_status,
+    }
+}
+
+/// Format a result for the table, noting the status if it wasn't a success,
+/// with the time to last byte and throughput of the download below it, and the
+/// redirects, other addresses, the ipv6 request, warm and resumed requests if there were any
+fn format_result(res: &MeasureResponse) -> String {
+    let duration = format!("{}ms", res.overall_duration.unwrap().as_millis());
+
+    let mut result = match res.status {
+        Some(status) if !(200..300).contains(&status) => format!("{} ({})", duration, status),
+        _ => duration,
     };
 
-    Ok(req)
+    match res.verification {
+        Some(ref verification) if !verification.verified => result.push_str("\nwrong body"),
+        _ => {}
+    }
+
+    if let Some(ttlb) = res.ttlb_duration {
+        result.push_str(&format!("\nttlb {}ms", ttlb.as_millis()));
+    }
+
+    if let Some(throughput) = res.average_throughput {
+        result.push_str(&format!("\n{}", format_rate(throughput)));
+    }
+
+    if let (Some(redirects), Some(chain)) = (&res.redirects, res.chain_duration) {
+        result.push_str(&format!(
+            "\n{} redirects {}ms",
+            redirects.len(),
+            chain.as_millis()
+        ));
+    }
+
+    if let Some(ref addresses) = res.addresses {
+        result.push_str(&format!(
+            "\n{} {}ms",
+            res.ip,
+            res.overall_duration.unwrap().as_millis()
+        ));
+
+        for address in addresses {
+            match address.measurement {
+                Some(ref measurement) => result.push_str(&format!(
+                    "\n{} {}ms",
+                    address.ip,
+                    measurement.overall_duration.unwrap().as_millis()
+                )),
+                None => result.push_str(&format!("\n{} failed", address.ip)),
+            }
+        }
+    }
+
+    if let Some(ref ipv6) = res.ipv6 {
+        result.push_str(&format!(
+            "\nipv6 {}ms",
+            ipv6.overall_duration.unwrap().as_millis()
+        ));
+    }
+
+    if res.ipv6_error.is_some() {
+        result.push_str("\nipv6 failed");
+    }
+
+    if let Some(warm) = res.warm.as_ref().filter(|warm| !warm.is_empty()) {
+        let average = collect::average(warm.iter(), warm.len());
+        result.push_str(&format!(
+            "\nwarm {}ms",
+            average.overall_duration.unwrap().as_millis()
+        ));
+    }
+
+    if res.warm_error.is_some() {
+        result.push_str("\nwarm failed");
+    }
+
+    if let Some(ref resumed) = res.resumed {
+        result.push_str(&format!(
+            "\nresumed {}ms",
+            resumed.overall_duration.unwrap().as_millis()
+        ));
+    }
+
+    result
+}
+
+/// Format a byte count in decimal units
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "kB", "MB", "GB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        return format!("{:.0} {}", value, UNITS[unit]);
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn format_rate(bytes_per_second: f64) -> String {
+    format!("{}/s", format_bytes(bytes_per_second))
 }
 
 fn print_average(label: String, measure: MeasureResponse) {
     println!("URL: {:#?}", label);
     println!("Average: {}ms", measure.ttfb_duration.as_millis());
     println!("Overall: {}ms", measure.overall_duration.unwrap().as_millis());
+
+    if let Some(ttlb) = measure.ttlb_duration {
+        println!("TTLB: {}ms", ttlb.as_millis());
+    }
+
+    if let Some(size) = measure.response_size {
+        let mut line = format!("Size: {}", format_bytes(size as f64));
+
+        if let Some(decoded) = measure.decoded_size.filter(|decoded| *decoded != size) {
+            line.push_str(&format!(", {} decoded", format_bytes(decoded as f64)));
+        }
+
+        if let Some(received) = measure.bytes_received {
+            line.push_str(&format!(", {} on the wire", format_bytes(received as f64)));
+        }
+
+        println!("{}", line);
+    }
+
+    if let (Some(average), Some(peak)) = (measure.average_throughput, measure.peak_throughput) {
+        println!(
+            "Throughput: {} average, {} peak",
+            format_rate(average),
+            format_rate(peak)
+        );
+    }
+}
+
+/// Print how many of the checked bodies matched, if they were checked
+fn print_verified(results: &[MeasureResponse]) {
+    let checked = results
+        .iter()
+        .filter_map(|res| res.verification.as_ref())
+        .collect::<Vec<_>>();
+
+    if checked.is_empty() {
+        return;
+    }
+
+    let verified = checked.iter().filter(|check| check.verified).count();
+
+    println!("Verified: {}/{}", verified, checked.len());
+}
+
+/// Print the average length and duration of the redirect chains, if redirects were followed
+fn print_redirects(results: &[MeasureResponse]) {
+    let chains = results
+        .iter()
+        .filter_map(|res| Some((res.redirects.as_ref()?.len(), res.chain_duration?)))
+        .collect::<Vec<_>>();
+
+    if chains.is_empty() {
+        return;
+    }
+
+    let hops = chains.iter().map(|(hops, _)| hops).sum::<usize>() as f64 / chains.len() as f64;
+    let duration = chains
+        .iter()
+        .map(|(_, duration)| *duration)
+        .sum::<Duration>()
+        / chains.len() as u32;
+
+    println!(
+        "Redirects: {:.1} per request, {}ms chain",
+        hops,
+        duration.as_millis()
+    );
+}
+
+/// Print the averages of every address measured and how often it failed, if the target's
+/// addresses were measured one by one
+fn print_addresses(results: &[MeasureResponse]) {
+    let mut addresses: Vec<(&str, Vec<&MeasureResponse>, usize)> = Vec::new();
+
+    for res in results {
+        let Some(ref others) = res.addresses else {
+            continue;
+        };
+
+        let measured = std::iter::once((res.ip.as_str(), Some(res)));
+        let others = others
+            .iter()
+            .map(|address| (address.ip.as_str(), address.measurement.as_ref()));
+
+        for (ip, measurement) in measured.chain(others) {
+            let index = match addresses.iter().position(|(known, _, _)| *known == ip) {
+                Some(index) => index,
+                None => {
+                    addresses.push((ip, Vec::new(), 0));
+                    addresses.len() - 1
+                }
+            };
+
+            match measurement {
+                Some(measurement) => addresses[index].1.push(measurement),
+                None => addresses[index].2 += 1,
+            }
+        }
+    }
+
+    for (ip, measurements, failed) in addresses {
+        if measurements.is_empty() {
+            println!("Address {}: {} failed", ip, failed);
+            continue;
+        }
+
+        let average = collect::average(measurements.iter().copied(), measurements.len());
+
+        println!(
+            "Address {}: {}ms, {}ms ttfb{}",
+            ip,
+            average.overall_duration.unwrap().as_millis(),
+            average.ttfb_duration.as_millis(),
+            match failed {
+                0 => String::new(),
+                failed => format!(", {} failed", failed),
+            }
+        );
+    }
+}
+
+/// Print the averages over ipv4 and over ipv6 of the samples both families were measured
+/// in, and how many failed over ipv6
+fn print_dual_stack(results: &[MeasureResponse]) {
+    let (ipv4, ipv6): (Vec<_>, Vec<_>) = results
+        .iter()
+        .filter_map(|res| Some((res, res.ipv6.as_deref()?)))
+        .unzip();
+
+    let failed = results
+        .iter()
+        .filter(|res| res.ipv6_error.is_some())
+        .count();
+    if failed > 0 {
+        println!("IPv6 failed: {}/{}", failed, results.len());
+    }
+
+    if ipv4.is_empty() {
+        return;
+    }
+
+    for (family, measurements) in [("IPv4", ipv4), ("IPv6", ipv6)] {
+        let average = collect::average(measurements.iter().copied(), measurements.len());
+
+        println!(
+            "{}: {}ms, {}ms ttfb",
+            family,
+            average.overall_duration.unwrap().as_millis(),
+            average.ttfb_duration.as_millis()
+        );
+    }
+}
+
+/// Print what the latest tls handshake negotiated and when the certificate expires
+fn print_tls(results: &[MeasureResponse]) {
+    let Some(tls) = results.iter().rev().find_map(|res| res.tls.as_ref()) else {
+        return;
+    };
+
+    let negotiated = [&tls.version, &tls.cipher_suite, &tls.alpn]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    println!("TLS: {}", negotiated.join(", "));
+
+    if let Some(ref certificate) = tls.certificate {
+        let expiry = match certificate.not_after.duration_since(SystemTime::now()) {
+            Ok(left) => format!("expires in {} days", left.as_secs() / 86400),
+            Err(_) => "expired".to_string(),
+        };
+
+        println!("Certificate: {} ({})", certificate.subject, expiry);
+    }
+}
+
+/// Print the averages of the warm and resumed requests next to those of the cold ones
+fn print_reuse(results: &[MeasureResponse]) {
+    let warm = results
+        .iter()
+        .filter_map(|res| res.warm.as_ref())
+        .flatten()
+        .collect::<Vec<_>>();
+
+    if !warm.is_empty() {
+        let average = collect::average(warm.iter().copied(), warm.len());
+
+        println!(
+            "Warm: {}ms, {}ms ttfb",
+            average.overall_duration.unwrap().as_millis(),
+            average.ttfb_duration.as_millis()
+        );
+    }
+
+    let resumed = results
+        .iter()
+        .filter_map(|res| res.resumed.as_deref())
+        .collect::<Vec<_>>();
+
+    if !resumed.is_empty() {
+        let average = collect::average(resumed.iter().copied(), resumed.len());
+        let accepted = resumed
+            .iter()
+            .filter(|res| res.tls.as_ref().is_some_and(|tls| tls.resumed))
+            .count();
+
+        println!(
+            "Resumed: {}ms, {}ms ttfb, {}/{} sessions resumed",
+            average.overall_duration.unwrap().as_millis(),
+            average.ttfb_duration.as_millis(),
+            accepted,
+            resumed.len()
+        );
+    }
 }
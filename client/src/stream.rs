@@ -0,0 +1,81 @@
+//! Reading the server sent events of a streamed batch from a measure service
+
+use futures::{Stream, StreamExt};
+use measure::{ErrorResponse, MeasureResponse};
+
+/// An event of a streamed batch
+#[derive(Debug)]
+pub enum SampleEvent {
+    Sample(MeasureResponse),
+    /// The batch failed, the stream ends
+    Error(ErrorResponse),
+    /// All the samples were taken, the stream ends
+    Done,
+}
+
+/// Parse the events out of the body of a stream response
+pub fn events(res: reqwest::Response) -> impl Stream<Item = anyhow::Result<SampleEvent>> {
+    futures::stream::unfold(
+        (res.bytes_stream().boxed(), Vec::new(), false),
+        |(mut body, mut buf, finished)| async move {
+            if finished {
+                return None;
+            }
+
+            loop {
+                if let Some(pos) = buf.windows(2).position(|w| w == b"\n\n") {
+                    let raw = buf.drain(..pos + 2).collect::<Vec<u8>>();
+
+                    match parse(&raw) {
+                        Ok(Some(event)) => {
+                            let finished = !matches!(event, SampleEvent::Sample(_));
+
+                            return Some((Ok(event), (body, buf, finished)));
+                        }
+                        // comments and keep alives
+                        Ok(None) => continue,
+                        Err(e) => return Some((Err(e), (body, buf, true))),
+                    }
+                }
+
+                match body.next().await {
+                    Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                    Some(Err(e)) => return Some((Err(e.into()), (body, buf, true))),
+                    None => {
+                        return Some((
+                            Err(anyhow::anyhow!(
+                                "the stream ended before the batch was done"
+                            )),
+                            (body, buf, true),
+                        ))
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Parse a single event, events without a name are ignored
+fn parse(raw: &[u8]) -> anyhow::Result<Option<SampleEvent>> {
+    let raw = std::str::from_utf8(raw)?;
+
+    let mut name = None;
+    let mut data = Vec::new();
+
+    for line in raw.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            name = Some(value.trim());
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+
+    let data = data.join("\n");
+
+    Ok(match name {
+        Some("sample") => Some(SampleEvent::Sample(serde_json::from_str(&data)?)),
+        Some("error") => Some(SampleEvent::Error(serde_json::from_str(&data)?)),
+        Some("done") => Some(SampleEvent::Done),
+        _ => None,
+    })
+}
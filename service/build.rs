@@ -0,0 +1,24 @@
+use std::process::Command;
+
+/// Embed the git commit the service is built from, `MEASURE_GIT_COMMIT` can be set
+/// when building outside of a checkout
+fn main() {
+    println!("cargo:rerun-if-env-changed=MEASURE_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+
+    if std::env::var("MEASURE_GIT_COMMIT").is_ok() {
+        return;
+    }
+
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=MEASURE_GIT_COMMIT={}", commit.trim());
+    }
+}
@@ -0,0 +1,398 @@
+//! Authentication of requests to the measure service
+//!
+//! A request is authenticated with either a bearer token, `Authorization: Bearer <secret>`,
+//! or a signature that keeps the secret off the wire,
+//! `Authorization: HMAC-SHA256 key_id=<id>, timestamp=<unix seconds>, nonce=<hex>, signature=<hex>`,
+//! where the signature is the hmac-sha256 of
+//! `<METHOD>\n<path and query>\n<timestamp>\n<nonce>\n<body>`. A nonce is only accepted once
+//! within the clock skew, so a signed request can't be replayed
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::MeasureError;
+
+const HMAC_SCHEME: &str = "HMAC-SHA256";
+
+/// How far a signed request's timestamp may be from the service's clock, in seconds
+pub const MAX_CLOCK_SKEW: u64 = 300;
+
+/// The most nonces remembered at once, more signed requests within the clock skew are refused
+const MAX_NONCES: usize = 100_000;
+
+/// The longest nonce accepted, in characters
+const MAX_NONCE_LEN: usize = 64;
+
+/// The credentials a client authenticates with
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    Bearer(String),
+    Hmac { key_id: String, secret: String },
+}
+
+impl Credentials {
+    /// The authorization header value for a request to the path, with its query if it has one
+    pub fn authorization(&self, method: &str, path_and_query: &str, body: &[u8]) -> String {
+        match self {
+            Credentials::Bearer(token) => format!("Bearer {}", token),
+            Credentials::Hmac { key_id, secret } => signed(
+                key_id,
+                secret,
+                method,
+                path_and_query,
+                unix_now(),
+                &format!("{:032x}", rand::random::<u128>()),
+                body,
+            ),
+        }
+    }
+}
+
+/// The keys the service accepts, a toml file mapping key ids to secrets
+#[derive(Debug)]
+pub struct KeyStore {
+    keys: HashMap<String, String>,
+    /// The nonces of signed requests, until their timestamp is outside the clock skew
+    nonces: Mutex<HashMap<String, u64>>,
+}
+
+impl KeyStore {
+    pub fn load(path: &Path) -> Result<Self, MeasureError> {
+        let contents = fs::read_to_string(path)?;
+        let keys: HashMap<String, String> = toml::from_str(&contents)
+            .map_err(|e| MeasureError::Internal(format!("Invalid keys file: {}", e)))?;
+
+        if keys.is_empty() {
+            return Err(MeasureError::Internal(
+                "The keys file has no keys".to_string(),
+            ));
+        }
+
+        Ok(KeyStore::new(keys))
+    }
+
+    fn new(keys: HashMap<String, String>) -> Self {
+        KeyStore {
+            keys,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check the authorization header of a request to the path, with its query if it has one,
+    /// against the keys
+    pub fn verify(
+        &self,
+        authorization: &str,
+        method: &str,
+        path_and_query: &str,
+        body: &[u8],
+    ) -> Result<(), MeasureError> {
+        let (scheme, params) = authorization
+            .split_once(' ')
+            .ok_or_else(|| MeasureError::Unauthorized("Malformed authorization".to_string()))?;
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            let token = params.trim().as_bytes();
+
+            return match self
+                .keys
+                .values()
+                .any(|secret| bool::from(secret.as_bytes().ct_eq(token)))
+            {
+                true => Ok(()),
+                false => Err(MeasureError::Unauthorized("Invalid token".to_string())),
+            };
+        }
+
+        if !scheme.eq_ignore_ascii_case(HMAC_SCHEME) {
+            return Err(MeasureError::Unauthorized(format!(
+                "Unsupported authorization scheme: {}",
+                scheme
+            )));
+        }
+
+        let params = params
+            .split(',')
+            .filter_map(|param| param.trim().split_once('='))
+            .collect::<HashMap<_, _>>();
+
+        let param = |name: &str| {
+            params
+                .get(name)
+                .copied()
+                .ok_or_else(|| MeasureError::Unauthorized(format!("Missing {}", name)))
+        };
+
+        let secret = self
+            .keys
+            .get(param("key_id")?)
+            .ok_or_else(|| MeasureError::Unauthorized("Unknown key id".to_string()))?;
+
+        let timestamp = param("timestamp")?
+            .parse::<u64>()
+            .map_err(|_| MeasureError::Unauthorized("Invalid timestamp".to_string()))?;
+
+        if unix_now().abs_diff(timestamp) > MAX_CLOCK_SKEW {
+            return Err(MeasureError::Unauthorized(
+                "Timestamp is outside the allowed clock skew".to_string(),
+            ));
+        }
+
+        let nonce = param("nonce")?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(MeasureError::Unauthorized("Invalid nonce".to_string()));
+        }
+
+        let signature = hex::decode(param("signature")?)
+            .map_err(|_| MeasureError::Unauthorized("Invalid signature".to_string()))?;
+
+        mac(secret, method, path_and_query, timestamp, nonce, body)
+            .verify_slice(&signature)
+            .map_err(|_| MeasureError::Unauthorized("Invalid signature".to_string()))?;
+
+        self.use_nonce(nonce, timestamp)
+    }
+
+    /// Remember a signed request's nonce, refusing one that was already used
+    fn use_nonce(&self, nonce: &str, timestamp: u64) -> Result<(), MeasureError> {
+        let now = unix_now();
+        let mut nonces = self.nonces.lock().expect("nonces lock poisoned");
+
+        // a replay of a forgotten nonce is refused for its timestamp anyway
+        nonces.retain(|_, timestamp| now.abs_diff(*timestamp) <= MAX_CLOCK_SKEW);
+
+        if nonces.contains_key(nonce) {
+            return Err(MeasureError::Unauthorized(
+                "Nonce has already been used".to_string(),
+            ));
+        }
+
+        if nonces.len() >= MAX_NONCES {
+            return Err(MeasureError::TooManyRequests(
+                "Too many signed requests within the clock skew".to_string(),
+            ));
+        }
+
+        nonces.insert(nonce.to_string(), timestamp);
+
+        Ok(())
+    }
+}
+
+fn signed(
+    key_id: &str,
+    secret: &str,
+    method: &str,
+    path_and_query: &str,
+    timestamp: u64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    let signature = mac(secret, method, path_and_query, timestamp, nonce, body)
+        .finalize()
+        .into_bytes();
+
+    format!(
+        "{} key_id={}, timestamp={}, nonce={}, signature={}",
+        HMAC_SCHEME,
+        key_id,
+        timestamp,
+        nonce,
+        hex::encode(signature)
+    )
+}
+
+fn mac(
+    secret: &str,
+    method: &str,
+    path_and_query: &str,
+    timestamp: u64,
+    nonce: &str,
+    body: &[u8],
+) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+
+    mac.update(method.to_uppercase().as_bytes());
+    mac.update(b"\n");
+    mac.update(path_and_query.as_bytes());
+    mac.update(b"\n");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b"\n");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+
+    mac
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time is after the unix epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_ID: &str = "ci";
+    const SECRET: &str = "a long random secret";
+
+    fn keys() -> KeyStore {
+        KeyStore::new(HashMap::from([(KEY_ID.to_string(), SECRET.to_string())]))
+    }
+
+    fn credentials() -> Credentials {
+        Credentials::Hmac {
+            key_id: KEY_ID.to_string(),
+            secret: SECRET.to_string(),
+        }
+    }
+
+    fn unauthorized(result: Result<(), MeasureError>) -> bool {
+        matches!(result, Err(MeasureError::Unauthorized(_)))
+    }
+
+    #[test]
+    fn signed_requests_verify() {
+        let keys = keys();
+        let authorization = credentials().authorization("post", "/batch", b"{}");
+
+        assert!(keys.verify(&authorization, "POST", "/batch", b"{}").is_ok());
+
+        let authorization = credentials().authorization("GET", "/history?target=a", b"");
+        assert!(keys
+            .verify(&authorization, "GET", "/history?target=a", b"")
+            .is_ok());
+    }
+
+    #[test]
+    fn signatures_cover_the_request() {
+        let keys = keys();
+        let authorization = || credentials().authorization("GET", "/history?target=a", b"");
+
+        assert!(unauthorized(keys.verify(
+            &authorization(),
+            "GET",
+            "/history?target=b",
+            b""
+        )));
+        assert!(unauthorized(keys.verify(
+            &authorization(),
+            "GET",
+            "/history",
+            b""
+        )));
+        assert!(unauthorized(keys.verify(
+            &authorization(),
+            "DELETE",
+            "/history?target=a",
+            b""
+        )));
+        assert!(unauthorized(keys.verify(
+            &authorization(),
+            "GET",
+            "/history?target=a",
+            b"{}"
+        )));
+    }
+
+    #[test]
+    fn bad_signatures_are_refused() {
+        let keys = keys();
+        let other = Credentials::Hmac {
+            key_id: KEY_ID.to_string(),
+            secret: "another secret".to_string(),
+        };
+        let unknown = Credentials::Hmac {
+            key_id: "other".to_string(),
+            secret: SECRET.to_string(),
+        };
+
+        for authorization in [
+            other.authorization("GET", "/info", b""),
+            unknown.authorization("GET", "/info", b""),
+            format!(
+                "{} key_id={}, timestamp={}, nonce=1, signature=zz",
+                HMAC_SCHEME,
+                KEY_ID,
+                unix_now()
+            ),
+            format!("{} key_id={}, nonce=1, signature=00", HMAC_SCHEME, KEY_ID),
+            "Basic Y2k6c2VjcmV0".to_string(),
+        ] {
+            assert!(unauthorized(keys.verify(
+                &authorization,
+                "GET",
+                "/info",
+                b""
+            )));
+        }
+    }
+
+    #[test]
+    fn timestamps_outside_the_clock_skew_are_refused() {
+        let keys = keys();
+        let at = |timestamp: u64| signed(KEY_ID, SECRET, "GET", "/info", timestamp, "1", b"");
+
+        let now = unix_now();
+        assert!(unauthorized(keys.verify(
+            &at(now - MAX_CLOCK_SKEW - 1),
+            "GET",
+            "/info",
+            b""
+        )));
+        assert!(unauthorized(keys.verify(
+            &at(now + MAX_CLOCK_SKEW + 1),
+            "GET",
+            "/info",
+            b""
+        )));
+        assert!(keys
+            .verify(&at(now - MAX_CLOCK_SKEW + 10), "GET", "/info", b"")
+            .is_ok());
+    }
+
+    #[test]
+    fn nonces_are_only_used_once() {
+        let keys = keys();
+        let authorization = credentials().authorization("POST", "/jobs", b"{}");
+
+        assert!(keys.verify(&authorization, "POST", "/jobs", b"{}").is_ok());
+        assert!(unauthorized(keys.verify(
+            &authorization,
+            "POST",
+            "/jobs",
+            b"{}"
+        )));
+
+        let authorization = credentials().authorization("POST", "/jobs", b"{}");
+        assert!(keys.verify(&authorization, "POST", "/jobs", b"{}").is_ok());
+    }
+
+    #[test]
+    fn bearer_tokens_verify() {
+        let keys = keys();
+
+        assert!(keys
+            .verify(&format!("Bearer {}", SECRET), "GET", "/info", b"")
+            .is_ok());
+        assert!(unauthorized(keys.verify(
+            "Bearer wrong",
+            "GET",
+            "/info",
+            b""
+        )));
+    }
+}
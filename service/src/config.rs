@@ -0,0 +1,264 @@
+use std::{fs, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use clap::Parser;
+use measure::{policy::TargetPolicy, MeasureDurationRequest, MeasureError, RedirectPolicy};
+use serde::Deserialize;
+
+use crate::schedule::{Cadence, Schedule};
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:3000";
+const DEFAULT_JOB_TTL: u64 = 60 * 60;
+const DEFAULT_HISTORY: &str = "history.jsonl";
+const DEFAULT_HISTORY_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Every option can also be set by its environment variable or in the toml config file,
+/// the command line takes precedence over the environment which takes precedence over the file
+#[derive(Parser)]
+pub struct CliArgs {
+    /// The toml config file to read
+    #[clap(long, env = "MEASURE_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// The addresses to listen on, can be repeated or comma separated
+    /// to listen on both ipv4 and ipv6, defaults to 0.0.0.0:3000
+    #[clap(long, env = "MEASURE_LISTEN", value_delimiter = ',')]
+    listen: Vec<SocketAddr>,
+
+    /// Serve https, with a self signed certificate unless a certificate and key are given
+    #[clap(long, env = "MEASURE_TLS")]
+    tls: bool,
+
+    /// The pem encoded certificate chain to serve https with
+    #[clap(long, env = "MEASURE_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// The pem encoded private key to serve https with
+    #[clap(long, env = "MEASURE_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// A toml file of `key_id = "secret"` pairs, requests must authenticate with one of the keys
+    /// as a bearer token or hmac signature, unauthenticated when not set
+    #[clap(long, env = "MEASURE_AUTH_KEYS")]
+    auth_keys: Option<PathBuf>,
+
+    /// A label for where the service runs, reported by the info route
+    #[clap(long, env = "MEASURE_REGION")]
+    region: Option<String>,
+
+    /// How many seconds a finished job is kept for, defaults to an hour
+    #[clap(long, env = "MEASURE_JOB_TTL")]
+    job_ttl: Option<u64>,
+
+    /// The file scheduled measurements are recorded in, defaults to history.jsonl
+    #[clap(long, env = "MEASURE_HISTORY")]
+    history: Option<PathBuf>,
+
+    /// How large the history file grows before it replaces the previous one, defaults to 16MiB
+    #[clap(long, env = "MEASURE_HISTORY_MAX_BYTES")]
+    history_max_bytes: Option<u64>,
+}
+
+/// The layout of the toml config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    listen: Option<Vec<SocketAddr>>,
+    tls: Option<TlsConfig>,
+    auth_keys: Option<PathBuf>,
+    policy: Option<TargetPolicy>,
+    limits: Option<Limits>,
+    region: Option<String>,
+    job_ttl: Option<u64>,
+    history: Option<PathBuf>,
+    history_max_bytes: Option<u64>,
+    #[serde(default)]
+    schedule: Vec<ScheduleConfig>,
+}
+
+/// A measurement the service takes on its own, every `every` seconds or on a `cron` schedule
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleConfig {
+    /// Defaults to the target url
+    name: Option<String>,
+    every: Option<u64>,
+    /// A cron expression with a seconds field, `0 */5 * * * *` runs every five minutes
+    cron: Option<String>,
+    request: MeasureDurationRequest,
+}
+
+/// The resolved configuration of the service
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    /// Serve https if set
+    pub tls: Option<TlsConfig>,
+    /// Authenticate requests with the keys in this file if set
+    pub auth_keys: Option<PathBuf>,
+    /// The targets the service will send requests to, only set in the config file
+    pub policy: TargetPolicy,
+    /// How much a request can ask the service to do, only set in the config file
+    pub limits: Limits,
+    pub region: Option<String>,
+    /// How long a finished job is kept for
+    pub job_ttl: Duration,
+    pub history: PathBuf,
+    pub history_max_bytes: u64,
+    /// The measurements to take on a schedule, only set in the config file
+    pub schedules: Vec<Schedule>,
+}
+
+/// The most a request can ask of the service, requests over a limit are refused
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The most samples a batch or job can take
+    pub max_times: usize,
+    /// The longest delay between samples, in milliseconds
+    pub max_delay: u64,
+    /// The most jitter added to the delay, in milliseconds
+    pub max_jitter: u64,
+    /// The most jobs running at once
+    pub max_jobs: usize,
+    /// The most warmup requests before a measurement
+    pub max_warmup: usize,
+    /// The most warm requests after a measurement
+    pub max_warm: usize,
+    /// The most redirects a measurement can follow
+    pub max_redirects: usize,
+    /// The most addresses of a target measured when every address is, the rest are skipped
+    pub max_addresses: usize,
+    /// The most hosts labelled in the metrics besides the allowed and scheduled ones
+    pub max_metric_targets: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_times: 1000,
+            max_delay: 60 * 1000,
+            max_jitter: 60 * 1000,
+            max_jobs: 16,
+            max_warmup: 10,
+            max_warm: 100,
+            max_redirects: 20,
+            max_addresses: 8,
+            max_metric_targets: 100,
+        }
+    }
+}
+
+impl Limits {
+    /// Refuse following more redirects than the limit allows
+    pub fn check_redirect(&self, redirect: RedirectPolicy) -> Result<(), MeasureError> {
+        match redirect {
+            RedirectPolicy::Follow { max } if max > self.max_redirects => {
+                Err(MeasureError::BadRequest(format!(
+                    "A measurement can follow at most {} redirects",
+                    self.max_redirects
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An empty tls section serves https with a self signed certificate
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl CliArgs {
+    pub fn config(self) -> anyhow::Result<Config> {
+        let file = match self.config {
+            Some(ref path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+
+                toml::from_str::<ConfigFile>(&contents)
+                    .with_context(|| format!("failed to parse config file {}", path.display()))?
+            }
+            None => ConfigFile::default(),
+        };
+
+        let listen = match (self.listen.is_empty(), file.listen) {
+            (false, _) => self.listen,
+            (true, Some(listen)) => listen,
+            (true, None) => vec![DEFAULT_LISTEN.parse().expect("valid default address")],
+        };
+
+        let tls = match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert: Some(cert),
+                key: Some(key),
+            }),
+            _ if self.tls => Some(file.tls.unwrap_or_default()),
+            _ => file.tls,
+        };
+
+        if let Some(ref tls) = tls {
+            if tls.cert.is_some() != tls.key.is_some() {
+                anyhow::bail!("a tls certificate and key must be given together");
+            }
+        }
+
+        let limits = file.limits.unwrap_or_default();
+
+        let schedules = file
+            .schedule
+            .into_iter()
+            .map(|schedule| schedule.schedule(&limits))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Config {
+            listen,
+            tls,
+            auth_keys: self.auth_keys.or(file.auth_keys),
+            policy: file.policy.unwrap_or_default(),
+            limits,
+            region: self.region.or(file.region),
+            job_ttl: Duration::from_secs(self.job_ttl.or(file.job_ttl).unwrap_or(DEFAULT_JOB_TTL)),
+            history: self
+                .history
+                .or(file.history)
+                .unwrap_or_else(|| DEFAULT_HISTORY.into()),
+            history_max_bytes: self
+                .history_max_bytes
+                .or(file.history_max_bytes)
+                .unwrap_or(DEFAULT_HISTORY_MAX_BYTES),
+            schedules,
+        })
+    }
+}
+
+impl ScheduleConfig {
+    fn schedule(self, limits: &Limits) -> anyhow::Result<Schedule> {
+        let name = self
+            .name
+            .unwrap_or_else(|| self.request.request.target.clone());
+
+        limits
+            .check_redirect(self.request.request.redirect)
+            .with_context(|| format!("schedule {} asks for too much", name))?;
+
+        let cadence = match (self.every, self.cron) {
+            (Some(0), None) => anyhow::bail!("schedule {} must run every second or longer", name),
+            (Some(every), None) => Cadence::Every(Duration::from_secs(every)),
+            (None, Some(ref cron)) => Cadence::Cron(Box::new(
+                cron::Schedule::from_str(cron)
+                    .with_context(|| format!("invalid cron expression for schedule {}", name))?,
+            )),
+            _ => anyhow::bail!("schedule {} needs exactly one of every or cron", name),
+        };
+
+        Ok(Schedule {
+            name,
+            cadence,
+            request: self.request,
+        })
+    }
+}
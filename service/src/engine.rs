@@ -0,0 +1,1099 @@
+//! The async measurement engine
+//!
+//! Every phase of a request (dns, tcp connect, tls handshake, request write, first byte)
+//! is driven directly on the tokio runtime and timed individually, so concurrent probes
+//! don't compete for the blocking pool
+//!
+//! Http/1.1 and http/2 run over tcp, http/3 over quic where the transport and tls
+//! handshakes are a single phase
+//!
+//! A connection can be kept open after its first request, to measure warm requests over it
+//! or resume its tls session on a new one
+//!
+//! Hosts are looked up with the system resolver unless the request chose a dns server
+//!
+//! The first address a host resolves to is connected to, unless the request chose an
+//! address family, with both families the addresses are raced like happy eyeballs does
+
+pub mod dns;
+mod encoding;
+mod http1;
+mod http2;
+mod http3;
+mod tls;
+mod transfer;
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use http::{
+    header::{
+        AUTHORIZATION, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION,
+        PROXY_AUTHORIZATION, TE, TRANSFER_ENCODING, UPGRADE,
+    },
+    HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Uri,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinSet,
+};
+use tokio_rustls::{client::TlsStream, rustls::pki_types::ServerName};
+use url::{Host, Position, Url};
+
+use self::transfer::{Counted, Transfer};
+
+use crate::{
+    policy::TargetPolicy, verify, AddressFamily, ConnectionKind, DnsResolver, HttpProtocol,
+    MeasureError, MeasureResponse, Phase, RequestBody, Verify,
+};
+
+/// How long each phase of a request can take before it fails, so a target that stops
+/// responding can't hold a probe open
+const DNS_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+const FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(30);
+const BODY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The largest response head and body read off a connection, a target can't grow the
+/// buffers without bound
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long happy eyeballs waits on a connection attempt before starting the next one, the
+/// delay rfc 8305 recommends
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// A connection the engine can speak http over, plain tcp or tls
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// A single http request to be measured
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub url: Url,
+    pub method: Method,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// The response headers to record in the measurement
+    pub capture_headers: Vec<HeaderName>,
+    /// The policy the resolved address of the target is checked against, unrestricted if none
+    pub policy: Option<Arc<TargetPolicy>>,
+    pub protocol: HttpProtocol,
+    /// The sha2-256 digest the response body is checked against
+    pub verify: Option<Vec<u8>>,
+    /// The addresses hosts are connected to instead of being resolved, by normalized host
+    pub resolve: HashMap<String, IpAddr>,
+    /// What hosts without an address in `resolve` are looked up with
+    pub resolver: dns::Resolver,
+    /// The addresses of the target that are connected to
+    pub family: AddressFamily,
+}
+
+impl Probe {
+    /// Parses the target url, only http and https targets are supported
+    pub fn new(target: &str, method: Method) -> Result<Self, MeasureError> {
+        let url = Url::parse(target)
+            .map_err(|e| MeasureError::BadRequest(format!("Invalid target url: {}", e)))?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(MeasureError::BadRequest(format!(
+                "Unsupported scheme: {}",
+                url.scheme()
+            )));
+        }
+
+        if url.host().is_none() {
+            return Err(MeasureError::BadRequest(
+                "Target url has no host".to_string(),
+            ));
+        }
+
+        Ok(Probe {
+            url,
+            method,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            capture_headers: Vec::new(),
+            policy: None,
+            protocol: HttpProtocol::default(),
+            verify: None,
+            resolve: HashMap::new(),
+            resolver: dns::Resolver::System,
+            family: AddressFamily::default(),
+        })
+    }
+
+    /// Add the headers to the request
+    pub fn with_headers(mut self, headers: &HashMap<String, String>) -> Result<Self, MeasureError> {
+        for (key, value) in headers {
+            self.headers.append(
+                HeaderName::from_str(key)
+                    .map_err(|e| MeasureError::BadRequest(format!("Invalid header name: {}", e)))?,
+                HeaderValue::from_str(value).map_err(|e| {
+                    MeasureError::BadRequest(format!("Invalid header value: {}", e))
+                })?,
+            );
+        }
+
+        Ok(self)
+    }
+
+    /// Set the body of the request, along with its content type unless one was already set
+    pub fn with_body(mut self, body: &RequestBody) -> Result<Self, MeasureError> {
+        let (content_type, body) = body.encode()?;
+
+        if !self.headers.contains_key(CONTENT_TYPE) {
+            self.headers
+                .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+
+        self.body = body;
+
+        Ok(self)
+    }
+
+    /// Restrict the probe to targets the policy allows, the url is checked now
+    /// and the address it resolves to before connecting
+    pub fn with_policy(mut self, policy: Arc<TargetPolicy>) -> Result<Self, MeasureError> {
+        policy.check_url(&self.url)?;
+        self.policy = Some(policy);
+
+        Ok(self)
+    }
+
+    /// Make the request with the http version, http/2 and http/3 need an https target
+    pub fn with_protocol(mut self, protocol: HttpProtocol) -> Result<Self, MeasureError> {
+        if protocol != HttpProtocol::H1 && self.url.scheme() != "https" {
+            return Err(MeasureError::BadRequest(format!(
+                "{:?} needs an https target",
+                protocol
+            )));
+        }
+
+        self.protocol = protocol;
+
+        Ok(self)
+    }
+
+    /// Check the response body, the expected digest is worked out now
+    pub fn with_verify(mut self, verify: &Verify) -> Result<Self, MeasureError> {
+        self.verify = Some(verify::expected_digest(verify, &self.url)?);
+
+        Ok(self)
+    }
+
+    /// Connect to the given addresses for these hosts instead of resolving them, like curl's
+    /// `--resolve`, the request is still made for the host of the url
+    pub fn with_resolve(mut self, resolve: &HashMap<String, IpAddr>) -> Result<Self, MeasureError> {
+        for (host, ip) in resolve {
+            // keyed the way the url normalizes its host, lowercase and punycode
+            let host = Host::parse(host).map_err(|e| {
+                MeasureError::BadRequest(format!("Invalid host to resolve {}: {}", host, e))
+            })?;
+
+            self.resolve.insert(host.to_string(), *ip);
+        }
+
+        Ok(self)
+    }
+
+    /// Look hosts up with the resolver, its server is checked against the policy if the probe
+    /// has one
+    pub fn with_resolver(mut self, resolver: &DnsResolver) -> Result<Self, MeasureError> {
+        self.resolver = dns::Resolver::new(resolver)?;
+
+        if let (Some(policy), Some(server)) = (&self.policy, self.resolver.server()) {
+            policy.check_ip(server.ip())?;
+        }
+
+        Ok(self)
+    }
+
+    /// Connect to addresses of the family, racing both needs tcp so it can't be done over
+    /// http/3
+    pub fn with_family(mut self, family: AddressFamily) -> Result<Self, MeasureError> {
+        if family == AddressFamily::Both && self.protocol == HttpProtocol::H3 {
+            return Err(MeasureError::BadRequest(
+                "Both address families can't be raced over H3".to_string(),
+            ));
+        }
+
+        self.family = family;
+
+        Ok(self)
+    }
+
+    /// The probe connecting to the address instead of resolving the target's host
+    pub fn pinned(&self, ip: IpAddr) -> Probe {
+        let mut probe = self.clone();
+
+        if let Some(Host::Domain(domain)) = self.url.host() {
+            probe.resolve.insert(domain.to_string(), ip);
+        }
+
+        probe
+    }
+
+    /// Record the named response headers in the measurement
+    pub fn with_capture_headers(mut self, names: &[String]) -> Result<Self, MeasureError> {
+        for name in names {
+            self.capture_headers
+                .push(HeaderName::from_str(name).map_err(|e| {
+                    MeasureError::BadRequest(format!("Invalid header name: {}", e))
+                })?);
+        }
+
+        Ok(self)
+    }
+
+    /// The probe following the redirect of the measured response, none if it wasn't one
+    ///
+    /// The next target is checked against the policy like the first one was
+    pub fn redirected(&self, measurement: &MeasureResponse) -> Result<Option<Probe>, MeasureError> {
+        let status = match measurement
+            .status
+            .and_then(|s| StatusCode::from_u16(s).ok())
+        {
+            Some(
+                status @ (StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT),
+            ) => status,
+            _ => return Ok(None),
+        };
+
+        let Some(ref location) = measurement.location else {
+            return Ok(None);
+        };
+
+        let url = self.url.join(location).map_err(|e| {
+            MeasureError::InvalidResponse(format!("Invalid redirect location: {}", e))
+        })?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(MeasureError::InvalidResponse(format!(
+                "Unsupported redirect to {}",
+                url
+            )));
+        }
+
+        let mut next = Probe {
+            url,
+            ..self.clone()
+        };
+
+        // see other, and moved or found for a post, are followed with a get without a body
+        if (status == StatusCode::SEE_OTHER && self.method != Method::HEAD)
+            || (matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND)
+                && self.method == Method::POST)
+        {
+            next.method = Method::GET;
+            next.body = Bytes::new();
+            next.headers.remove(CONTENT_TYPE);
+            next.headers.remove(CONTENT_LENGTH);
+        }
+
+        // a pinned host and credentials are for the origin they were given for
+        if next.url.origin() != self.url.origin() {
+            for name in [HOST, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION] {
+                next.headers.remove(name);
+            }
+        }
+
+        // http/2 and http/3 need https, a redirect to http is followed over http/1.1
+        if next.url.scheme() != "https" {
+            next.protocol = HttpProtocol::H1;
+        }
+
+        if let Some(ref policy) = next.policy {
+            policy.check_url(&next.url)?;
+        }
+
+        Ok(Some(next))
+    }
+}
+
+/// Run the probe against its target, timing each phase of the request
+///
+/// Failures are tagged with the phase they happened in and the timings collected before it
+pub async fn measure(probe: &Probe) -> Result<MeasureResponse, MeasureError> {
+    Ok(fetch(probe).await?.0)
+}
+
+/// Run the probe like [`measure`], also returning the decoded response body
+pub async fn fetch(probe: &Probe) -> Result<(MeasureResponse, Bytes), MeasureError> {
+    let (_, measurement, body) = open(probe).await?;
+
+    Ok((measurement, body))
+}
+
+/// Every address the probe's target resolves to, in the order the resolver returned them
+pub async fn lookup(probe: &Probe) -> Result<Vec<IpAddr>, MeasureError> {
+    let (addrs, _) = resolve(probe).await.map_err(|e| {
+        let measurement = MeasureResponse {
+            timestamp: Some(SystemTime::now()),
+            ..Default::default()
+        };
+
+        e.during(Phase::Dns, &measurement)
+    })?;
+
+    let mut ips = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        if !ips.contains(&addr.ip()) {
+            ips.push(addr.ip());
+        }
+    }
+
+    Ok(ips)
+}
+
+/// Run the probe like [`fetch`], keeping the connection open for more requests
+pub async fn open(probe: &Probe) -> Result<(Connection, MeasureResponse, Bytes), MeasureError> {
+    let mut measurement = MeasureResponse {
+        timestamp: Some(SystemTime::now()),
+        ..Default::default()
+    };
+    let start = Instant::now();
+
+    let (addrs, dns_lookup_duration) = resolve(probe)
+        .await
+        .map_err(|e| e.during(Phase::Dns, &measurement))?;
+    let addrs = match probe.family {
+        AddressFamily::Both => interleave(addrs),
+        AddressFamily::Any | AddressFamily::V4 | AddressFamily::V6 => vec![addrs[0]],
+    };
+    measurement.ip = addrs[0].ip().to_string();
+    measurement.dns_lookup_duration = dns_lookup_duration;
+
+    if let Some(ref policy) = probe.policy {
+        for addr in &addrs {
+            policy.check_ip(addr.ip())?;
+        }
+    }
+
+    let handshake = tls::Handshake::new(probe.protocol);
+
+    Connection::establish(probe, &addrs, handshake, measurement, start).await
+}
+
+/// A connection to the target kept open after its first request, to measure more requests
+/// over it or resume its tls session
+pub struct Connection {
+    addr: SocketAddr,
+    http: Http,
+    /// The tls client of an https connection over tcp, quic keeps its own
+    handshake: Option<tls::Handshake>,
+    /// The bytes read off a tcp connection
+    received: Arc<AtomicU64>,
+    /// The bytes received that were counted in earlier measurements
+    counted: u64,
+}
+
+enum Http {
+    Http1 { io: Box<dyn Io>, reusable: bool },
+    Http2(http2::Connection),
+    Http3(http3::Connection),
+}
+
+impl Connection {
+    /// Connect to the first of the addresses to answer and measure the probe's request,
+    /// continuing the measurement that started at `start`
+    async fn establish(
+        probe: &Probe,
+        addrs: &[SocketAddr],
+        handshake: tls::Handshake,
+        mut measurement: MeasureResponse,
+        start: Instant,
+    ) -> Result<(Self, MeasureResponse, Bytes), MeasureError> {
+        let mut connection = match probe.protocol {
+            HttpProtocol::H3 => Connection {
+                addr: addrs[0],
+                http: Http::Http3(
+                    http3::connect(probe, addrs[0], handshake, &mut measurement).await?,
+                ),
+                handshake: None,
+                received: Arc::default(),
+                counted: 0,
+            },
+            HttpProtocol::H1 | HttpProtocol::H2 => {
+                connect_tcp(probe, addrs, handshake, &mut measurement).await?
+            }
+        };
+
+        let downloaded = connection.exchange(probe, &mut measurement).await?;
+        let body = finish(probe, &mut measurement, start, downloaded).await?;
+
+        Ok((connection, measurement, body))
+    }
+
+    /// Measure the probe's request again over the open connection
+    pub async fn fetch(&mut self, probe: &Probe) -> Result<(MeasureResponse, Bytes), MeasureError> {
+        let mut measurement = MeasureResponse {
+            timestamp: Some(SystemTime::now()),
+            connection: ConnectionKind::Warm,
+            ip: self.addr.ip().to_string(),
+            ..Default::default()
+        };
+        let start = Instant::now();
+
+        let downloaded = self.exchange(probe, &mut measurement).await?;
+        let body = finish(probe, &mut measurement, start, downloaded).await?;
+
+        Ok((measurement, body))
+    }
+
+    /// Close the connection and measure the probe's request over a new one to the same
+    /// address, offering the tls session of this one
+    pub async fn resume(
+        self,
+        probe: &Probe,
+    ) -> Result<(Connection, MeasureResponse, Bytes), MeasureError> {
+        let handshake = match self.http {
+            Http::Http3(ref connection) => Some(connection.handshake()),
+            Http::Http1 { .. } | Http::Http2(_) => self.handshake.as_ref(),
+        }
+        .ok_or_else(|| {
+            MeasureError::BadRequest("Only https connections can be resumed".to_string())
+        })?
+        .resume();
+        let addr = self.addr;
+
+        // the session outlives the connection, like it would for a client coming back later
+        drop(self);
+
+        let measurement = MeasureResponse {
+            timestamp: Some(SystemTime::now()),
+            connection: ConnectionKind::Resumed,
+            ip: addr.ip().to_string(),
+            ..Default::default()
+        };
+
+        Self::establish(probe, &[addr], handshake, measurement, Instant::now()).await
+    }
+
+    /// Make the probe's request with the http version the connection speaks
+    async fn exchange(
+        &mut self,
+        probe: &Probe,
+        measurement: &mut MeasureResponse,
+    ) -> Result<Downloaded, MeasureError> {
+        let body = match self.http {
+            Http::Http1 {
+                ref mut io,
+                ref mut reusable,
+            } => {
+                measurement.protocol = Some("http/1.1".to_string());
+
+                if !*reusable {
+                    return Err(MeasureError::Protocol(
+                        "the target closed the connection after the last response".to_string(),
+                    )
+                    .during(Phase::Send, measurement));
+                }
+
+                let (body, keep_alive) = exchange_http1(io, probe, measurement).await?;
+                *reusable = keep_alive;
+
+                body
+            }
+            Http::Http2(ref mut connection) => {
+                measurement.protocol = Some("h2".to_string());
+
+                connection.exchange(probe, measurement).await?
+            }
+            Http::Http3(ref mut connection) => {
+                measurement.protocol = Some("h3".to_string());
+
+                connection.exchange(probe, measurement).await?
+            }
+        };
+
+        let received = match self.http {
+            Http::Http3(ref connection) => connection.received(),
+            Http::Http1 { .. } | Http::Http2(_) => self.received.load(Ordering::Relaxed),
+        };
+        measurement.bytes_received = Some(received - self.counted);
+        self.counted = received;
+
+        Ok(body)
+    }
+}
+
+/// Connect over tcp to the first of the addresses to answer, with tls for https targets,
+/// ready to speak the http version the target agreed to
+async fn connect_tcp(
+    probe: &Probe,
+    addrs: &[SocketAddr],
+    handshake: tls::Handshake,
+    measurement: &mut MeasureResponse,
+) -> Result<Connection, MeasureError> {
+    let tcp_start = Instant::now();
+    let (stream, addr) = timed(CONNECT_TIMEOUT, happy_eyeballs(addrs))
+        .await
+        .map_err(|e| e.during(Phase::TcpConnect, measurement))?;
+    measurement.tcp_connect_duration = tcp_start.elapsed();
+    measurement.ip = addr.ip().to_string();
+
+    let stream = Counted::new(stream);
+    let received = stream.received();
+
+    if probe.url.scheme() != "https" {
+        return Ok(Connection {
+            addr,
+            http: Http::Http1 {
+                io: Box::new(stream),
+                reusable: true,
+            },
+            handshake: None,
+            received,
+            counted: 0,
+        });
+    }
+
+    let tls_start = Instant::now();
+    let stream = timed(
+        HANDSHAKE_TIMEOUT,
+        tls_handshake(&probe.url, addr, stream, &handshake),
+    )
+    .await
+    .map_err(|e| {
+        measurement.tls = handshake.presented();
+        e.during(Phase::TlsHandshake, measurement)
+    })?;
+    measurement.tls_handshake_duration = Some(tls_start.elapsed());
+    measurement.tls = Some(handshake.negotiated(stream.get_ref().1));
+
+    let http = if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+        Http::Http2(http2::Connection::new(Box::new(stream)))
+    } else {
+        Http::Http1 {
+            io: Box::new(stream),
+            reusable: true,
+        }
+    };
+
+    Ok(Connection {
+        addr,
+        http,
+        handshake: Some(handshake),
+        received,
+        counted: 0,
+    })
+}
+
+/// Make a http/1.1 request, returns the body and whether the connection can be reused
+async fn exchange_http1(
+    stream: &mut Box<dyn Io>,
+    probe: &Probe,
+    measurement: &mut MeasureResponse,
+) -> Result<(Downloaded, bool), MeasureError> {
+    let send_start = Instant::now();
+    timed(SEND_TIMEOUT, send(stream, probe))
+        .await
+        .map_err(|e| e.during(Phase::Send, measurement))?;
+    let sent = Instant::now();
+    measurement.http_get_send_duration = sent - send_start;
+
+    let mut reader = http1::ResponseReader::new(stream);
+    let head = timed(FIRST_BYTE_TIMEOUT, reader.read_head())
+        .await
+        .map_err(|e| e.during(Phase::FirstByte, measurement))?;
+    measurement.ttfb_duration = head.first_byte - sent;
+    record_head(measurement, probe, head.status, &head.headers);
+
+    let body = timed(BODY_TIMEOUT, reader.read_body(&probe.method, &head))
+        .await
+        .map_err(|e| e.during(Phase::Body, measurement))?;
+    let body = downloaded(measurement, sent, reader.transfer(), &head.headers, body)?;
+
+    Ok((body, reader.reusable(&head)))
+}
+
+/// Run a phase of the request, failing it if it takes longer than the timeout
+async fn timed<T>(
+    timeout: Duration,
+    phase: impl Future<Output = Result<T, MeasureError>>,
+) -> Result<T, MeasureError> {
+    match tokio::time::timeout(timeout, phase).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out after {}s", timeout.as_secs()),
+        )
+        .into()),
+    }
+}
+
+/// Fail a body that grew larger than the engine reads
+fn check_body_size(size: usize) -> Result<(), MeasureError> {
+    if size > MAX_BODY_SIZE {
+        return Err(MeasureError::InvalidResponse(format!(
+            "the response body is larger than {} bytes",
+            MAX_BODY_SIZE
+        )));
+    }
+
+    Ok(())
+}
+
+/// A response body as it arrived, with the content encodings to undo once the timings
+/// are taken
+struct Downloaded {
+    body: Bytes,
+    encodings: Vec<String>,
+}
+
+/// Complete a measurement started at `start` whose body was received, returns the body
+/// with its content encoding undone
+async fn finish(
+    probe: &Probe,
+    measurement: &mut MeasureResponse,
+    start: Instant,
+    downloaded: Downloaded,
+) -> Result<Bytes, MeasureError> {
+    measurement.overall_duration = Some(start.elapsed());
+
+    // decoding and hashing aren't part of the request, they're done once the timings are taken
+    let body = match encoding::decode(downloaded.encodings, downloaded.body.clone())
+        .await
+        .map_err(|e| e.during(Phase::Body, measurement))?
+    {
+        Some(decoded) => {
+            measurement.decoded_size = Some(decoded.len() as u64);
+
+            decoded
+        }
+        None => downloaded.body,
+    };
+
+    if let Some(ref expected) = probe.verify {
+        measurement.verification = Some(verify::check(expected, &body));
+    }
+
+    Ok(body)
+}
+
+/// Record the download of the body, returns it with the encodings it arrived in
+fn downloaded(
+    measurement: &mut MeasureResponse,
+    sent: Instant,
+    transfer: &Transfer,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Downloaded, MeasureError> {
+    let size = body.len() as u64;
+    measurement.ttlb_duration = Some(transfer.last_byte() - sent);
+    measurement.response_size = Some(size);
+
+    if let Some((average, peak)) = transfer.throughput(size) {
+        measurement.average_throughput = Some(average);
+        measurement.peak_throughput = Some(peak);
+    }
+
+    let encodings = encoding::encodings(headers).map_err(|e| e.during(Phase::Body, measurement))?;
+
+    Ok(Downloaded { body, encodings })
+}
+
+/// The probe as a request for http/2 and http/3, which carry the host in the uri
+/// and have no connection specific headers
+fn request(probe: &Probe) -> Result<Request<()>, MeasureError> {
+    let authority = match probe.headers.get(HOST) {
+        Some(host) => host.as_bytes(),
+        None => probe.url[Position::BeforeHost..Position::AfterPort].as_bytes(),
+    };
+
+    let uri = Uri::builder()
+        .scheme(probe.url.scheme())
+        .authority(authority)
+        .path_and_query(&probe.url[Position::BeforePath..Position::AfterQuery])
+        .build()
+        .map_err(|e| MeasureError::BadRequest(format!("Invalid target url: {}", e)))?;
+
+    let mut request = Request::builder()
+        .method(probe.method.clone())
+        .uri(uri)
+        .body(())
+        .map_err(|e| MeasureError::BadRequest(e.to_string()))?;
+
+    let headers = request.headers_mut();
+
+    if !probe.headers.contains_key(CONTENT_LENGTH)
+        && (!probe.body.is_empty() || matches!(probe.method, Method::POST | Method::PUT))
+    {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(probe.body.len()));
+    }
+
+    for (name, value) in probe.headers.iter() {
+        let connection_specific = matches!(name.as_str(), "keep-alive" | "proxy-connection")
+            || [HOST, CONNECTION, TRANSFER_ENCODING, UPGRADE].contains(name)
+            || (name == TE && value != "trailers");
+
+        if !connection_specific {
+            headers.append(name, value.clone());
+        }
+    }
+
+    Ok(request)
+}
+
+/// Record the status and captured headers of the response, and where it redirects to
+fn record_head(
+    measurement: &mut MeasureResponse,
+    probe: &Probe,
+    status: StatusCode,
+    headers: &HeaderMap,
+) {
+    measurement.status = Some(status.as_u16());
+    measurement.headers = capture_headers(headers, &probe.capture_headers);
+
+    if status.is_redirection() {
+        measurement.location = headers
+            .get(LOCATION)
+            .map(|location| String::from_utf8_lossy(location.as_bytes()).into_owned());
+    }
+}
+
+/// Pick the captured headers out of the response, joining repeated headers with a comma
+fn capture_headers(headers: &HeaderMap, names: &[HeaderName]) -> HashMap<String, String> {
+    names
+        .iter()
+        .filter(|name| headers.contains_key(*name))
+        .map(|name| {
+            let values = headers
+                .get_all(name)
+                .iter()
+                .map(|value| String::from_utf8_lossy(value.as_bytes()))
+                .collect::<Vec<_>>();
+
+            (name.to_string(), values.join(", "))
+        })
+        .collect()
+}
+
+async fn connect(addr: SocketAddr) -> Result<TcpStream, MeasureError> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+/// Connect to the first of the addresses to answer, in order, starting on the next one when
+/// an attempt fails or hasn't connected within the attempt delay
+async fn happy_eyeballs(addrs: &[SocketAddr]) -> Result<(TcpStream, SocketAddr), MeasureError> {
+    if let [addr] = *addrs {
+        return Ok((connect(addr).await?, addr));
+    }
+
+    let mut pending = addrs.iter().copied();
+    let mut attempts = JoinSet::new();
+    let mut error = None;
+
+    loop {
+        if let Some(addr) = pending.next() {
+            attempts.spawn(async move { (addr, connect(addr).await) });
+        }
+
+        let attempt = tokio::select! {
+            attempt = attempts.join_next() => attempt,
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => continue,
+        };
+
+        // the attempts still going are aborted when the set is dropped
+        match attempt {
+            Some(Ok((addr, Ok(stream)))) => return Ok((stream, addr)),
+            Some(Ok((_, Err(e)))) => error = Some(e),
+            Some(Err(e)) => return Err(MeasureError::Internal(e.to_string())),
+            None => return Err(error.expect("every address was attempted")),
+        }
+    }
+}
+
+/// The addresses alternating between ipv6 and ipv4, starting with ipv6, the order happy
+/// eyeballs attempts them in
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut interleaved = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+async fn tls_handshake(
+    url: &Url,
+    addr: SocketAddr,
+    stream: Counted<TcpStream>,
+    handshake: &tls::Handshake,
+) -> Result<TlsStream<Counted<TcpStream>>, MeasureError> {
+    Ok(handshake
+        .connector()
+        .connect(server_name(url, addr)?, stream)
+        .await?)
+}
+
+async fn send(stream: &mut Box<dyn Io>, probe: &Probe) -> Result<(), MeasureError> {
+    stream.write_all(&http1::encode_request(probe)).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+/// Resolve the host of the probe's url to its addresses in the probe's family, never empty,
+/// ip literals and overridden hosts skip the lookup and have no dns duration
+async fn resolve(probe: &Probe) -> Result<(Vec<SocketAddr>, Option<Duration>), MeasureError> {
+    let port = probe
+        .url
+        .port_or_known_default()
+        .ok_or_else(|| MeasureError::BadRequest("Target url has no port".to_string()))?;
+
+    let (addrs, dns_lookup_duration) = match probe.url.host() {
+        Some(Host::Domain(domain)) => match probe.resolve.get(domain) {
+            Some(ip) => (vec![SocketAddr::new(*ip, port)], None),
+            None => {
+                let dns_start = Instant::now();
+                let addrs = timed(
+                    DNS_TIMEOUT,
+                    probe.resolver.addresses(domain, port, probe.family),
+                )
+                .await?;
+
+                (addrs, Some(dns_start.elapsed()))
+            }
+        },
+        Some(Host::Ipv4(ip)) => (vec![SocketAddr::new(ip.into(), port)], None),
+        Some(Host::Ipv6(ip)) => (vec![SocketAddr::new(ip.into(), port)], None),
+        None => {
+            return Err(MeasureError::BadRequest(
+                "Target url has no host".to_string(),
+            ))
+        }
+    };
+
+    // overrides and ip literals can be of the other family
+    let (addrs, message) = match probe.family {
+        AddressFamily::V4 => (
+            addrs.into_iter().filter(SocketAddr::is_ipv4).collect(),
+            "no ipv4 addresses found for host",
+        ),
+        AddressFamily::V6 => (
+            addrs.into_iter().filter(SocketAddr::is_ipv6).collect(),
+            "no ipv6 addresses found for host",
+        ),
+        AddressFamily::Any | AddressFamily::Both => (addrs, "no addresses found for host"),
+    };
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+    }
+
+    Ok((addrs, dns_lookup_duration))
+}
+
+fn server_name(url: &Url, addr: SocketAddr) -> Result<ServerName<'static>, MeasureError> {
+    match url.host() {
+        Some(Host::Domain(domain)) => Ok(ServerName::try_from(domain.to_string())?),
+        _ => Ok(ServerName::IpAddress(addr.ip().into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(status: u16, location: &str) -> MeasureResponse {
+        MeasureResponse {
+            status: Some(status),
+            location: Some(location.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn post() -> Probe {
+        Probe::new("https://example.com/form", Method::POST)
+            .unwrap()
+            .with_headers(&HashMap::from([(
+                "Authorization".to_string(),
+                "Bearer secret".to_string(),
+            )]))
+            .unwrap()
+            .with_body(&RequestBody::Text("hello".to_string()))
+            .unwrap()
+    }
+
+    fn follow(probe: &Probe, status: u16, location: &str) -> Probe {
+        probe
+            .redirected(&redirect(status, location))
+            .unwrap()
+            .expect("a redirect")
+    }
+
+    #[test]
+    fn see_other_is_followed_with_a_get() {
+        let next = follow(&post(), 303, "/done");
+
+        assert_eq!(next.url.as_str(), "https://example.com/done");
+        assert_eq!(next.method, Method::GET);
+        assert!(next.body.is_empty());
+        assert!(!next.headers.contains_key(CONTENT_TYPE));
+        assert!(next.headers.contains_key(AUTHORIZATION));
+    }
+
+    #[test]
+    fn moved_and_found_posts_are_followed_with_a_get() {
+        for status in [301, 302] {
+            let next = follow(&post(), status, "/moved");
+
+            assert_eq!(next.method, Method::GET);
+            assert!(next.body.is_empty());
+        }
+
+        let put = Probe {
+            method: Method::PUT,
+            ..post()
+        };
+        let next = follow(&put, 301, "/moved");
+        assert_eq!(next.method, Method::PUT);
+        assert_eq!(next.body, "hello");
+    }
+
+    #[test]
+    fn temporary_and_permanent_redirects_keep_the_method_and_body() {
+        for status in [307, 308] {
+            let next = follow(&post(), status, "https://example.com/other");
+
+            assert_eq!(next.method, Method::POST);
+            assert_eq!(next.body, "hello");
+            assert!(next.headers.contains_key(CONTENT_TYPE));
+        }
+    }
+
+    #[test]
+    fn credentials_are_dropped_for_other_origins() {
+        let next = follow(&post(), 307, "https://example.com:8443/form");
+        assert!(!next.headers.contains_key(AUTHORIZATION));
+
+        let next = follow(&post(), 307, "https://other.test/form");
+        assert!(!next.headers.contains_key(AUTHORIZATION));
+
+        let next = follow(&post(), 307, "/form?again");
+        assert!(next.headers.contains_key(AUTHORIZATION));
+    }
+
+    #[test]
+    fn redirects_to_http_are_followed_over_http1() {
+        let probe = Probe::new("https://example.com/", Method::GET)
+            .unwrap()
+            .with_protocol(HttpProtocol::H2)
+            .unwrap();
+
+        assert_eq!(follow(&probe, 302, "/").protocol, HttpProtocol::H2);
+        assert_eq!(
+            follow(&probe, 302, "http://example.com/").protocol,
+            HttpProtocol::H1
+        );
+    }
+
+    #[test]
+    fn only_redirects_with_a_location_are_followed() {
+        let probe = post();
+
+        assert!(probe.redirected(&redirect(200, "/")).unwrap().is_none());
+        assert!(probe.redirected(&redirect(304, "/")).unwrap().is_none());
+        assert!(probe
+            .redirected(&MeasureResponse {
+                status: Some(302),
+                ..Default::default()
+            })
+            .unwrap()
+            .is_none());
+        assert!(probe
+            .redirected(&redirect(302, "ftp://example.com/"))
+            .is_err());
+    }
+
+    #[test]
+    fn redirects_are_checked_against_the_policy() {
+        let probe = post()
+            .with_policy(Arc::new(TargetPolicy::default()))
+            .unwrap();
+
+        assert!(matches!(
+            probe.redirected(&redirect(302, "http://127.0.0.1/")),
+            Err(MeasureError::TargetDenied(_))
+        ));
+    }
+
+    #[test]
+    fn resolver_servers_are_checked_against_the_policy() {
+        let probe = || {
+            Probe::new("https://example.com/", Method::GET)
+                .unwrap()
+                .with_policy(Arc::new(TargetPolicy::default()))
+                .unwrap()
+        };
+        let server = |ip: &str| DnsResolver::Udp {
+            server: ip.to_string(),
+        };
+
+        for denied in ["127.0.0.1", "10.0.0.53", "[::1]:5353"] {
+            assert!(
+                matches!(
+                    probe().with_resolver(&server(denied)),
+                    Err(MeasureError::TargetDenied(_))
+                ),
+                "{} should be denied",
+                denied
+            );
+        }
+
+        assert!(probe().with_resolver(&server("9.9.9.9")).is_ok());
+
+        // without a policy any server can be used
+        assert!(Probe::new("https://example.com/", Method::GET)
+            .unwrap()
+            .with_resolver(&server("127.0.0.1"))
+            .is_ok());
+    }
+
+    #[test]
+    fn interleave_alternates_families_starting_with_ipv6() {
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        let (v4a, v4b, v4c) = (
+            addr("192.0.2.1:443"),
+            addr("192.0.2.2:443"),
+            addr("192.0.2.3:443"),
+        );
+        let (v6a, v6b) = (addr("[2001:db8::1]:443"), addr("[2001:db8::2]:443"));
+
+        assert_eq!(
+            interleave(vec![v4a, v4b, v6a, v4c, v6b]),
+            vec![v6a, v4a, v6b, v4b, v4c]
+        );
+        assert_eq!(interleave(vec![v6a, v6b, v4a]), vec![v6a, v4a, v6b]);
+        assert_eq!(interleave(vec![v4a, v4b]), vec![v4a, v4b]);
+        assert!(interleave(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,520 @@
+//! Dns lookups with the resolver a request chose
+//!
+//! The system resolver is getaddrinfo, dns servers are queried with hickory without a cache
+//! or the hosts file and over a connection of their own, so every lookup is timed cold like
+//! the connections to the target are
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Instant, SystemTime},
+};
+
+use dns_lookup::{AddrFamily, AddrInfoHints, LookupErrorKind, SockType};
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, ResolveHosts, ResolverConfig, ResolverOpts},
+    name_server::TokioConnectionProvider,
+    proto::{
+        op::ResponseCode,
+        rr::{Record, RecordType},
+        xfer::Protocol,
+        ProtoErrorKind,
+    },
+    ResolveError, TokioResolver,
+};
+use url::Host;
+
+use super::tls;
+use crate::{
+    AddressFamily, DnsAnswer, DnsQuery, DnsResolver, DnsResponse, MeasureError, MeasureResponse,
+    Phase,
+};
+
+/// The resolver of a request, with the address of its server parsed
+#[derive(Debug, Clone, Default)]
+pub enum Resolver {
+    #[default]
+    System,
+    Server(NameServerConfig),
+}
+
+impl Resolver {
+    pub fn new(resolver: &DnsResolver) -> Result<Self, MeasureError> {
+        let (server, protocol, port, name) = match resolver {
+            DnsResolver::System => return Ok(Resolver::System),
+            DnsResolver::Udp { server } => (server, Protocol::Udp, 53, None),
+            DnsResolver::Tcp { server } => (server, Protocol::Tcp, 53, None),
+            DnsResolver::Tls { server, name } => (server, Protocol::Tls, 853, Some(name)),
+            DnsResolver::Https { server, name, .. } => (server, Protocol::Https, 443, Some(name)),
+        };
+
+        let addr = server
+            .parse::<SocketAddr>()
+            .or_else(|_| server.parse().map(|ip| SocketAddr::new(ip, port)))
+            .map_err(|_| {
+                MeasureError::BadRequest(format!("Invalid dns server address: {}", server))
+            })?;
+
+        let mut config = NameServerConfig::new(addr, protocol);
+        config.tls_dns_name = name.cloned();
+
+        if let DnsResolver::Https { path, .. } = resolver {
+            config.http_endpoint = path.clone();
+        }
+
+        Ok(Resolver::Server(config))
+    }
+
+    /// The address of the dns server, none for the system resolver
+    pub fn server(&self) -> Option<SocketAddr> {
+        match self {
+            Resolver::System => None,
+            Resolver::Server(config) => Some(config.socket_addr),
+        }
+    }
+
+    /// The addresses of the host in the family, only its records are looked up, empty if
+    /// it has none
+    pub async fn addresses(
+        &self,
+        host: &str,
+        port: u16,
+        family: AddressFamily,
+    ) -> Result<Vec<SocketAddr>, MeasureError> {
+        let ips = match self {
+            Resolver::System => {
+                let hint = match family {
+                    AddressFamily::V4 => Some(AddrFamily::Inet),
+                    AddressFamily::V6 => Some(AddrFamily::Inet6),
+                    AddressFamily::Any | AddressFamily::Both => None,
+                };
+
+                getaddrinfo(host, hint).await?
+            }
+            Resolver::Server(config) => {
+                let strategy = match family {
+                    AddressFamily::V4 => LookupIpStrategy::Ipv4Only,
+                    AddressFamily::V6 => LookupIpStrategy::Ipv6Only,
+                    AddressFamily::Any | AddressFamily::Both => LookupIpStrategy::Ipv4AndIpv6,
+                };
+
+                match client(config, strategy).lookup_ip(host).await {
+                    Ok(lookup) => lookup.iter().collect(),
+                    Err(e) if e.is_no_records_found() => Vec::new(),
+                    Err(e) => return Err(dns_error(e)),
+                }
+            }
+        };
+
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Query the records of a type for the name
+    async fn query(&self, name: &str, record_type: RecordType) -> Result<DnsQuery, MeasureError> {
+        let config = match self {
+            Resolver::System => return system_query(name, record_type).await,
+            Resolver::Server(config) => config,
+        };
+
+        let client = client(config, LookupIpStrategy::default());
+        let start = Instant::now();
+        let lookup = client.lookup(name, record_type).await;
+        let duration = start.elapsed();
+
+        let (response_code, answers) = match lookup {
+            Ok(lookup) => (
+                ResponseCode::NoError,
+                lookup.records().iter().map(answer).collect(),
+            ),
+            Err(e) => match e.proto().map(|e| e.kind()) {
+                Some(ProtoErrorKind::NoRecordsFound { response_code, .. }) => {
+                    (*response_code, Vec::new())
+                }
+                _ => return Err(dns_error(e)),
+            },
+        };
+
+        Ok(DnsQuery {
+            record_type: record_type.to_string(),
+            duration,
+            response_code: Some(format!("{:?}", response_code)),
+            answers,
+        })
+    }
+}
+
+/// Look up the name, querying each of the record types in turn
+pub async fn lookup(
+    resolver: &Resolver,
+    name: &str,
+    record_types: &[String],
+) -> Result<DnsResponse, MeasureError> {
+    let name = match Host::parse(name) {
+        Ok(Host::Domain(domain)) => domain,
+        Ok(_) => {
+            return Err(MeasureError::BadRequest(
+                "Only host names can be looked up".to_string(),
+            ))
+        }
+        Err(e) => return Err(MeasureError::BadRequest(format!("Invalid name: {}", e))),
+    };
+
+    let mut types = Vec::with_capacity(record_types.len());
+    for record_type in record_types {
+        let record_type = RecordType::from_str(&record_type.to_uppercase()).map_err(|_| {
+            MeasureError::BadRequest(format!("Unsupported record type: {}", record_type))
+        })?;
+
+        if matches!(resolver, Resolver::System)
+            && !matches!(record_type, RecordType::A | RecordType::AAAA)
+        {
+            return Err(MeasureError::BadRequest(
+                "The system resolver only looks up A and AAAA records".to_string(),
+            ));
+        }
+
+        types.push(record_type);
+    }
+
+    let timestamp = SystemTime::now();
+    let mut queries = Vec::with_capacity(types.len());
+
+    for record_type in types {
+        let query = super::timed(super::DNS_TIMEOUT, resolver.query(&name, record_type));
+        queries.push(query.await.map_err(|e| {
+            let partial = MeasureResponse {
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
+
+            e.during(Phase::Dns, &partial)
+        })?);
+    }
+
+    Ok(DnsResponse {
+        timestamp,
+        name,
+        queries,
+    })
+}
+
+/// A resolver asking only the server, every lookup starts from nothing
+fn client(config: &NameServerConfig, strategy: LookupIpStrategy) -> TokioResolver {
+    let mut options = ResolverOpts::default();
+    options.cache_size = 0;
+    options.use_hosts_file = ResolveHosts::Never;
+    options.ip_strategy = strategy;
+    options.tls_config = tls::dns_config();
+
+    TokioResolver::builder_with_config(
+        ResolverConfig::from_parts(None, Vec::new(), vec![config.clone()]),
+        TokioConnectionProvider::default(),
+    )
+    .with_options(options)
+    .build()
+}
+
+/// Getaddrinfo for the address family of the record type, it doesn't expose the records
+/// themselves, so the answers are only the addresses
+async fn system_query(name: &str, record_type: RecordType) -> Result<DnsQuery, MeasureError> {
+    let family = match record_type {
+        RecordType::AAAA => AddrFamily::Inet6,
+        _ => AddrFamily::Inet,
+    };
+
+    let start = Instant::now();
+    let ips = getaddrinfo(name, Some(family)).await?;
+    let duration = start.elapsed();
+
+    Ok(DnsQuery {
+        record_type: record_type.to_string(),
+        duration,
+        response_code: None,
+        answers: ips
+            .into_iter()
+            .map(|ip| DnsAnswer {
+                name: name.to_string(),
+                record_type: record_type.to_string(),
+                ttl: None,
+                data: ip.to_string(),
+            })
+            .collect(),
+    })
+}
+
+/// The addresses getaddrinfo has for the host, of either family without a hint, empty if
+/// it has none
+async fn getaddrinfo(host: &str, family: Option<AddrFamily>) -> Result<Vec<IpAddr>, MeasureError> {
+    let hints = AddrInfoHints {
+        address: family.map_or(0, Into::into),
+        socktype: SockType::Stream.into(),
+        ..Default::default()
+    };
+
+    let host = host.to_string();
+    let ips = tokio::task::spawn_blocking(move || -> io::Result<Vec<IpAddr>> {
+        match dns_lookup::getaddrinfo(Some(&host), None, Some(hints)) {
+            Ok(addrs) => {
+                let mut ips = Vec::new();
+                for addr in addrs {
+                    let ip = addr?.sockaddr.ip();
+                    // getaddrinfo can list an address once per line of the hosts file
+                    if !ips.contains(&ip) {
+                        ips.push(ip);
+                    }
+                }
+
+                Ok(ips)
+            }
+            Err(e) if matches!(e.kind(), LookupErrorKind::NoName | LookupErrorKind::NoData) => {
+                Ok(Vec::new())
+            }
+            Err(e) => Err(e.into()),
+        }
+    })
+    .await
+    .map_err(|e| MeasureError::Internal(e.to_string()))??;
+
+    Ok(ips)
+}
+
+fn answer(record: &Record) -> DnsAnswer {
+    DnsAnswer {
+        name: record.name().to_string(),
+        record_type: record.record_type().to_string(),
+        ttl: Some(record.ttl()),
+        data: record.data().to_string(),
+    }
+}
+
+fn dns_error(e: ResolveError) -> MeasureError {
+    MeasureError::Protocol(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::proto::{
+        op::{Message, MessageType},
+        rr::{
+            rdata::{A, AAAA},
+            RData,
+        },
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+    };
+
+    use super::*;
+
+    const TTL: u32 = 300;
+
+    /// The answer of the stub server, `a.test` has an address of each family and
+    /// `v4.test` only an ipv4 one, anything else doesn't exist
+    fn respond(query: &[u8]) -> Vec<u8> {
+        let query = Message::from_vec(query).unwrap();
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .set_recursion_available(true)
+            .add_queries(query.queries().to_vec());
+
+        let question = &query.queries()[0];
+        let name = question.name().to_ascii();
+        let rdata = match (name.as_str(), question.query_type()) {
+            ("a.test." | "v4.test.", RecordType::A) => Some(RData::A(A::new(192, 0, 2, 1))),
+            ("a.test.", RecordType::AAAA) => {
+                Some(RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)))
+            }
+            ("a.test." | "v4.test.", _) => None,
+            _ => {
+                response.set_response_code(ResponseCode::NXDomain);
+                None
+            }
+        };
+
+        if let Some(rdata) = rdata {
+            response.add_answer(Record::from_rdata(question.name().clone(), TTL, rdata));
+        }
+
+        response.to_vec().unwrap()
+    }
+
+    async fn udp_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                socket.send_to(&respond(&buf[..len]), peer).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    async fn tcp_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    // every message is prefixed with its length
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut query = vec![0; len as usize];
+                        stream.read_exact(&mut query).await.unwrap();
+
+                        let response = respond(&query);
+                        stream.write_u16(response.len() as u16).await.unwrap();
+                        stream.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn udp(addr: SocketAddr) -> Resolver {
+        Resolver::new(&DnsResolver::Udp {
+            server: addr.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn types(types: &[&str]) -> Vec<String> {
+        types.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn new_defaults_the_port() {
+        let resolver = Resolver::new(&DnsResolver::Tls {
+            server: "192.0.2.53".to_string(),
+            name: "dns.test".to_string(),
+        })
+        .unwrap();
+
+        assert_eq!(resolver.server(), Some("192.0.2.53:853".parse().unwrap()));
+        assert_eq!(Resolver::System.server(), None);
+        assert!(matches!(
+            Resolver::new(&DnsResolver::Udp {
+                server: "dns.test".to_string()
+            }),
+            Err(MeasureError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn addresses_of_the_family() {
+        let resolver = udp(udp_server().await);
+        let v4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+
+        let mut any = resolver
+            .addresses("a.test", 443, AddressFamily::Any)
+            .await
+            .unwrap();
+        any.sort();
+        assert_eq!(any, vec![v4, v6]);
+
+        assert_eq!(
+            resolver
+                .addresses("a.test", 443, AddressFamily::V4)
+                .await
+                .unwrap(),
+            vec![v4]
+        );
+        assert_eq!(
+            resolver
+                .addresses("a.test", 443, AddressFamily::V6)
+                .await
+                .unwrap(),
+            vec![v6]
+        );
+        assert!(resolver
+            .addresses("v4.test", 443, AddressFamily::V6)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(resolver
+            .addresses("missing.test", 443, AddressFamily::Any)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn lookup_answers_with_ttls() {
+        let addr = tcp_server().await;
+        let resolver = Resolver::new(&DnsResolver::Tcp {
+            server: addr.to_string(),
+        })
+        .unwrap();
+
+        let response = lookup(&resolver, "a.test", &types(&["a", "AAAA"]))
+            .await
+            .unwrap();
+
+        assert_eq!(response.name, "a.test");
+        assert_eq!(response.queries.len(), 2);
+
+        let a = &response.queries[0];
+        assert_eq!(a.record_type, "A");
+        assert_eq!(a.response_code.as_deref(), Some("NoError"));
+        assert_eq!(a.answers.len(), 1);
+        assert_eq!(a.answers[0].name, "a.test.");
+        assert_eq!(a.answers[0].ttl, Some(TTL));
+        assert_eq!(a.answers[0].data, "192.0.2.1");
+
+        let aaaa = &response.queries[1];
+        assert_eq!(aaaa.record_type, "AAAA");
+        assert_eq!(aaaa.answers[0].data, "2001:db8::1");
+    }
+
+    #[tokio::test]
+    async fn lookup_reports_missing_names() {
+        let resolver = udp(udp_server().await);
+
+        let response = lookup(&resolver, "missing.test", &types(&["A"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.queries[0].response_code.as_deref(),
+            Some("NXDomain")
+        );
+        assert!(response.queries[0].answers.is_empty());
+
+        let response = lookup(&resolver, "v4.test", &types(&["AAAA"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.queries[0].response_code.as_deref(),
+            Some("NoError")
+        );
+        assert!(response.queries[0].answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lookup_rejects_bad_requests() {
+        let resolver = udp(udp_server().await);
+
+        for (name, record_types) in [("192.0.2.1", types(&["A"])), ("a.test", types(&["BOGUS"]))] {
+            assert!(matches!(
+                lookup(&resolver, name, &record_types).await,
+                Err(MeasureError::BadRequest(_))
+            ));
+        }
+
+        assert!(matches!(
+            lookup(&Resolver::System, "a.test", &types(&["TXT"])).await,
+            Err(MeasureError::BadRequest(_))
+        ));
+    }
+}
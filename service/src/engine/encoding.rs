@@ -0,0 +1,94 @@
+//! Undoing the content encoding of a response body
+
+use std::io::Read;
+
+use bytes::Bytes;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use http::{header::CONTENT_ENCODING, HeaderMap};
+
+use crate::MeasureError;
+
+/// The largest body decoding can produce, a small body can expand to any size
+const MAX_DECODED_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The encodings in the headers, in the order they were applied
+pub fn encodings(headers: &HeaderMap) -> Result<Vec<String>, MeasureError> {
+    let mut encodings = Vec::new();
+
+    for value in headers.get_all(CONTENT_ENCODING) {
+        let value = value
+            .to_str()
+            .map_err(|_| MeasureError::InvalidResponse("invalid content-encoding".to_string()))?;
+
+        encodings.extend(
+            value
+                .split(',')
+                .map(|encoding| encoding.trim().to_ascii_lowercase())
+                .filter(|encoding| !encoding.is_empty()),
+        );
+    }
+
+    Ok(encodings)
+}
+
+/// Decode the body by the encodings, last applied first, on the blocking pool
+///
+/// Returns `None` if an encoding isn't supported, the body is left as it arrived
+pub async fn decode(encodings: Vec<String>, body: Bytes) -> Result<Option<Bytes>, MeasureError> {
+    if encodings.iter().all(|encoding| encoding == "identity") {
+        return Ok(Some(body));
+    }
+
+    let supported = |encoding: &String| {
+        matches!(
+            encoding.as_str(),
+            "identity" | "gzip" | "x-gzip" | "deflate" | "br"
+        )
+    };
+
+    if !encodings.iter().all(supported) {
+        return Ok(None);
+    }
+
+    tokio::task::spawn_blocking(move || decode_all(&encodings, body))
+        .await
+        .map_err(|e| MeasureError::Internal(e.to_string()))?
+        .map(Some)
+}
+
+fn decode_all(encodings: &[String], body: Bytes) -> Result<Bytes, MeasureError> {
+    let mut body = body;
+
+    for encoding in encodings.iter().rev() {
+        let mut decoded = Vec::new();
+
+        let result = match encoding.as_str() {
+            "gzip" | "x-gzip" => MultiGzDecoder::new(&body[..])
+                .take(MAX_DECODED_SIZE + 1)
+                .read_to_end(&mut decoded),
+            // http's deflate is the zlib format
+            "deflate" => ZlibDecoder::new(&body[..])
+                .take(MAX_DECODED_SIZE + 1)
+                .read_to_end(&mut decoded),
+            "br" => brotli_decompressor::Decompressor::new(&body[..], 4096)
+                .take(MAX_DECODED_SIZE + 1)
+                .read_to_end(&mut decoded),
+            _ => continue,
+        };
+
+        result.map_err(|e| {
+            MeasureError::InvalidResponse(format!("invalid {} body: {}", encoding, e))
+        })?;
+
+        if decoded.len() as u64 > MAX_DECODED_SIZE {
+            return Err(MeasureError::InvalidResponse(format!(
+                "the decoded body is larger than {} bytes",
+                MAX_DECODED_SIZE
+            )));
+        }
+
+        body = Bytes::from(decoded);
+    }
+
+    Ok(body)
+}
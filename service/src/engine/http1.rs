@@ -0,0 +1,538 @@
+//! A minimal http/1.1 client, just enough to time a request without a client library
+//! deciding when bytes hit the wire
+
+use std::{io, time::Instant};
+
+use bytes::{Buf, Bytes, BytesMut};
+use http::{
+    header::{CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+    HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+};
+use tokio::io::AsyncReadExt;
+use url::Position;
+
+use super::{check_body_size, transfer::Transfer, Io, Probe, MAX_HEAD_SIZE};
+use crate::MeasureError;
+
+const MAX_HEADERS: usize = 128;
+
+/// Serialize the probe into a http/1.1 request
+pub fn encode_request(probe: &Probe) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(256 + probe.body.len());
+
+    buf.extend_from_slice(probe.method.as_str().as_bytes());
+    buf.push(b' ');
+    buf.extend_from_slice(probe.url[Position::BeforePath..Position::AfterQuery].as_bytes());
+    buf.extend_from_slice(b" HTTP/1.1\r\n");
+
+    if !probe.headers.contains_key(HOST) {
+        write_header(
+            &mut buf,
+            &HOST,
+            probe.url[Position::BeforeHost..Position::AfterPort].as_bytes(),
+        );
+    }
+
+    if !probe.headers.contains_key(CONTENT_LENGTH)
+        && (!probe.body.is_empty() || matches!(probe.method, Method::POST | Method::PUT))
+    {
+        write_header(
+            &mut buf,
+            &CONTENT_LENGTH,
+            probe.body.len().to_string().as_bytes(),
+        );
+    }
+
+    for (name, value) in probe.headers.iter() {
+        write_header(&mut buf, name, value.as_bytes());
+    }
+
+    buf.extend_from_slice(b"\r\n");
+    buf.extend_from_slice(&probe.body);
+
+    buf
+}
+
+fn write_header(buf: &mut Vec<u8>, name: &HeaderName, value: &[u8]) {
+    buf.extend_from_slice(name.as_str().as_bytes());
+    buf.extend_from_slice(b": ");
+    buf.extend_from_slice(value);
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// The parsed status line and headers of a response
+#[derive(Debug)]
+pub struct Head {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// When the first byte of the response was read off the connection
+    pub first_byte: Instant,
+    /// Whether the target will keep the connection open for another request
+    pub keep_alive: bool,
+}
+
+/// Reads a http/1.1 response off a connection
+pub struct ResponseReader<'a> {
+    io: &'a mut dyn Io,
+    buf: BytesMut,
+    /// Started by the first byte read off the connection
+    transfer: Option<Transfer>,
+    /// Whether the target closed the connection
+    eof: bool,
+}
+
+impl<'a> ResponseReader<'a> {
+    pub fn new(io: &'a mut dyn Io) -> Self {
+        ResponseReader {
+            io,
+            buf: BytesMut::with_capacity(8 * 1024),
+            transfer: None,
+            eof: false,
+        }
+    }
+
+    /// Read more bytes from the connection into the buffer, returns 0 on eof
+    async fn fill(&mut self) -> io::Result<usize> {
+        let n = self.io.read_buf(&mut self.buf).await?;
+
+        if n > 0 {
+            self.transfer.get_or_insert_with(Transfer::start).record(n);
+        } else {
+            self.eof = true;
+        }
+
+        Ok(n)
+    }
+
+    async fn fill_or_eof(&mut self) -> Result<(), MeasureError> {
+        match self.fill().await? {
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the status line and headers, skipping any informational responses
+    pub async fn read_head(&mut self) -> Result<Head, MeasureError> {
+        loop {
+            let parsed = {
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut response = httparse::Response::new(&mut headers);
+
+                match response.parse(&self.buf)? {
+                    httparse::Status::Complete(len) => {
+                        let status = StatusCode::from_u16(response.code.unwrap_or_default())
+                            .map_err(|e| MeasureError::InvalidResponse(e.to_string()))?;
+                        let version = response.version.unwrap_or_default();
+
+                        let mut map = HeaderMap::with_capacity(response.headers.len());
+                        for header in response.headers.iter() {
+                            map.append(
+                                HeaderName::from_bytes(header.name.as_bytes())
+                                    .map_err(|e| MeasureError::InvalidResponse(e.to_string()))?,
+                                HeaderValue::from_bytes(header.value)
+                                    .map_err(|e| MeasureError::InvalidResponse(e.to_string()))?,
+                            );
+                        }
+
+                        Some((len, status, version, map))
+                    }
+                    httparse::Status::Partial if self.buf.len() > MAX_HEAD_SIZE => {
+                        return Err(MeasureError::InvalidResponse(format!(
+                            "the response head is larger than {} bytes",
+                            MAX_HEAD_SIZE
+                        )));
+                    }
+                    httparse::Status::Partial => None,
+                }
+            };
+
+            match parsed {
+                Some((len, status, version, headers)) => {
+                    self.buf.advance(len);
+
+                    // 101 switches protocols, anything else informational is followed by the real response
+                    if status.is_informational() && status != StatusCode::SWITCHING_PROTOCOLS {
+                        continue;
+                    }
+
+                    return Ok(Head {
+                        status,
+                        keep_alive: keep_alive(version, &headers),
+                        headers,
+                        first_byte: self.transfer().first_byte(),
+                    });
+                }
+                None => self.fill_or_eof().await?,
+            }
+        }
+    }
+
+    /// The arrival of the response, from the first byte read
+    pub fn transfer(&self) -> &Transfer {
+        self.transfer.as_ref().expect("bytes were read")
+    }
+
+    /// Whether another request can be made on the connection once the body was read,
+    /// the response must have left the connection open with nothing more on it
+    pub fn reusable(&self, head: &Head) -> bool {
+        head.keep_alive && !self.eof && self.buf.is_empty()
+    }
+
+    /// Read the body of the response according to its framing, returns the decoded body
+    pub async fn read_body(&mut self, method: &Method, head: &Head) -> Result<Bytes, MeasureError> {
+        if method == Method::HEAD
+            || head.status.is_informational()
+            || head.status == StatusCode::NO_CONTENT
+            || head.status == StatusCode::NOT_MODIFIED
+        {
+            return Ok(Bytes::new());
+        }
+
+        let chunked = head
+            .headers
+            .get_all(TRANSFER_ENCODING)
+            .iter()
+            .any(|v| v.as_bytes().eq_ignore_ascii_case(b"chunked"));
+
+        if chunked {
+            return self.read_chunked().await;
+        }
+
+        match head.headers.get(CONTENT_LENGTH) {
+            Some(len) => {
+                let len = len
+                    .to_str()
+                    .ok()
+                    .and_then(|len| len.trim().parse::<usize>().ok())
+                    .ok_or_else(|| {
+                        MeasureError::InvalidResponse("invalid content-length".to_string())
+                    })?;
+                check_body_size(len)?;
+
+                self.read_exact(len).await
+            }
+            None => self.read_to_end().await,
+        }
+    }
+
+    async fn read_exact(&mut self, len: usize) -> Result<Bytes, MeasureError> {
+        while self.buf.len() < len {
+            self.fill_or_eof().await?;
+        }
+
+        Ok(self.buf.split_to(len).freeze())
+    }
+
+    async fn read_to_end(&mut self) -> Result<Bytes, MeasureError> {
+        while self.fill().await? > 0 {
+            check_body_size(self.buf.len())?;
+        }
+
+        Ok(self.buf.split().freeze())
+    }
+
+    async fn read_line(&mut self) -> Result<Bytes, MeasureError> {
+        loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buf.split_to(pos).freeze();
+                self.buf.advance(2);
+
+                return Ok(line);
+            }
+
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(MeasureError::InvalidResponse(
+                    "chunk line is too long".to_string(),
+                ));
+            }
+
+            self.fill_or_eof().await?;
+        }
+    }
+
+    async fn read_chunked(&mut self) -> Result<Bytes, MeasureError> {
+        let mut body = BytesMut::new();
+
+        loop {
+            let line = self.read_line().await?;
+            let size = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|line| line.split(';').next())
+                .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                .ok_or_else(|| MeasureError::InvalidResponse("invalid chunk size".to_string()))?;
+
+            if size == 0 {
+                // skip any trailers up to the terminating empty line
+                while !self.read_line().await?.is_empty() {}
+
+                return Ok(body.freeze());
+            }
+
+            check_body_size(body.len().saturating_add(size))?;
+            body.extend_from_slice(&self.read_exact(size).await?);
+
+            if !self.read_line().await?.is_empty() {
+                return Err(MeasureError::InvalidResponse(
+                    "chunk is longer than its size".to_string(),
+                ));
+            }
+        }
+    }
+}
+
+/// Http/1.1 connections stay open unless the response closes them, http/1.0 ones only
+/// if it asks for keep-alive
+fn keep_alive(version: u8, headers: &HeaderMap) -> bool {
+    let has = |option: &str| {
+        headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(option))
+    };
+
+    match version {
+        1 => !has("close"),
+        _ => has("keep-alive"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::{engine::MAX_BODY_SIZE, RequestBody};
+
+    /// A connection the response is sent on, the target keeps it open unless `close`
+    fn connection(response: impl Into<Vec<u8>>, close: bool) -> DuplexStream {
+        let (client, mut server) = duplex(16 * 1024);
+        let response = response.into();
+
+        tokio::spawn(async move {
+            // the reader gives up on responses over the caps, leaving the rest unwritten
+            let _ = server.write_all(&response).await;
+
+            if !close {
+                std::future::pending::<()>().await;
+            }
+        });
+
+        client
+    }
+
+    /// Read the response off the connection, with the body and whether it can be reused
+    async fn read(
+        mut io: DuplexStream,
+        method: Method,
+    ) -> Result<(Head, Bytes, bool), MeasureError> {
+        let mut reader = ResponseReader::new(&mut io);
+        let head = reader.read_head().await?;
+        let body = reader.read_body(&method, &head).await?;
+        let reusable = reader.reusable(&head);
+
+        Ok((head, body, reusable))
+    }
+
+    async fn get(response: &str, close: bool) -> Result<(Head, Bytes, bool), MeasureError> {
+        read(connection(response, close), Method::GET).await
+    }
+
+    fn invalid<T>(result: Result<T, MeasureError>) -> bool {
+        matches!(result, Err(MeasureError::InvalidResponse(_)))
+    }
+
+    #[tokio::test]
+    async fn content_length_bodies() {
+        let (head, body, reusable) = get(
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\nx-test: a\r\n\r\nhello",
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(head.status, StatusCode::OK);
+        assert_eq!(head.headers["x-test"], "a");
+        assert_eq!(body, "hello");
+        assert!(reusable);
+
+        let (_, body, _) = read(
+            connection("HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n", false),
+            Method::HEAD,
+        )
+        .await
+        .unwrap();
+        assert!(body.is_empty());
+
+        assert!(get("HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhel", true)
+            .await
+            .is_err());
+        assert!(invalid(
+            get("HTTP/1.1 200 OK\r\ncontent-length: x\r\n\r\n", false).await
+        ));
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies() {
+        let (_, body, reusable) = get(
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n\
+             5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nx-trailer: 1\r\n\r\n",
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(body, "hello world");
+        assert!(reusable);
+
+        assert!(invalid(
+            get(
+                "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\nzz\r\n",
+                false
+            )
+            .await
+        ));
+        assert!(invalid(
+            get(
+                "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n2\r\nhello\r\n0\r\n\r\n",
+                false
+            )
+            .await
+        ));
+    }
+
+    #[tokio::test]
+    async fn bodies_without_framing_are_read_to_eof() {
+        let (_, body, reusable) = get("HTTP/1.1 200 OK\r\n\r\nhello world", true)
+            .await
+            .unwrap();
+
+        assert_eq!(body, "hello world");
+        assert!(!reusable);
+    }
+
+    #[tokio::test]
+    async fn informational_responses_are_skipped() {
+        let (head, body, _) = get(
+            "HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 103 Early Hints\r\nlink: </style.css>\r\n\r\n\
+             HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok",
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(head.status, StatusCode::OK);
+        assert!(!head.headers.contains_key("link"));
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn connections_are_kept_alive_by_version() {
+        for (response, expected) in [
+            ("HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok", true),
+            (
+                "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 2\r\n\r\nok",
+                false,
+            ),
+            (
+                "HTTP/1.1 200 OK\r\nconnection: upgrade, Close\r\ncontent-length: 2\r\n\r\nok",
+                false,
+            ),
+            ("HTTP/1.0 200 OK\r\ncontent-length: 2\r\n\r\nok", false),
+            (
+                "HTTP/1.0 200 OK\r\nconnection: keep-alive\r\ncontent-length: 2\r\n\r\nok",
+                true,
+            ),
+        ] {
+            let (head, _, reusable) = get(response, false).await.unwrap();
+
+            assert_eq!(head.keep_alive, expected, "{}", response);
+            assert_eq!(reusable, expected, "{}", response);
+        }
+
+        // more bytes than the response leave the connection in an unknown state
+        let (_, _, reusable) = get(
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nokHTTP/1.1",
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(!reusable);
+    }
+
+    #[tokio::test]
+    async fn heads_over_the_cap_are_refused() {
+        let mut response = b"HTTP/1.1 200 OK\r\n".to_vec();
+        while response.len() <= MAX_HEAD_SIZE + 1024 {
+            response.extend_from_slice(b"x-filler: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+        }
+
+        assert!(invalid(
+            read(connection(response, false), Method::GET).await
+        ));
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_cap_are_refused() {
+        let length = format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert!(invalid(get(&length, false).await));
+
+        let chunk = format!(
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n{:x}\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert!(invalid(get(&chunk, false).await));
+
+        let mut unframed = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        unframed.resize(unframed.len() + MAX_BODY_SIZE + 1, b'a');
+        assert!(invalid(read(connection(unframed, true), Method::GET).await));
+    }
+
+    fn encoded(probe: &Probe) -> String {
+        String::from_utf8(encode_request(probe)).unwrap()
+    }
+
+    #[test]
+    fn requests_get_a_host() {
+        let probe = Probe::new("http://example.com:8080/path?q=1#fragment", Method::GET).unwrap();
+
+        assert_eq!(
+            encoded(&probe),
+            "GET /path?q=1 HTTP/1.1\r\nhost: example.com:8080\r\n\r\n"
+        );
+
+        let probe = probe
+            .with_headers(&[("Host".to_string(), "other.test".to_string())].into())
+            .unwrap();
+        assert_eq!(
+            encoded(&probe),
+            "GET /path?q=1 HTTP/1.1\r\nhost: other.test\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn requests_with_bodies_get_a_content_length() {
+        let probe = Probe::new("https://example.com", Method::POST).unwrap();
+        assert_eq!(
+            encoded(&probe),
+            "POST / HTTP/1.1\r\nhost: example.com\r\ncontent-length: 0\r\n\r\n"
+        );
+
+        let probe = probe
+            .with_body(&RequestBody::Text("hello".to_string()))
+            .unwrap();
+        let request = encoded(&probe);
+        assert!(request.contains("\r\ncontent-length: 5\r\n"));
+        assert!(request.ends_with("\r\n\r\nhello"));
+
+        let probe = probe
+            .with_headers(&[("Content-Length".to_string(), "5".to_string())].into())
+            .unwrap();
+        assert_eq!(encoded(&probe).matches("content-length").count(), 1);
+    }
+}
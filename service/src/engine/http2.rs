@@ -0,0 +1,108 @@
+//! Http/2 over a tls connection that negotiated h2 with alpn
+//!
+//! The connection is driven by its own task, the request and response are timed from
+//! the stream handles
+
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use h2::client::{ResponseFuture, SendRequest};
+
+use super::{
+    check_body_size, downloaded, record_head, timed, transfer::Transfer, Downloaded, Io, Probe,
+    BODY_TIMEOUT, FIRST_BYTE_TIMEOUT, SEND_TIMEOUT,
+};
+use crate::{MeasureError, MeasureResponse, Phase};
+
+/// A http/2 connection, its preface is sent along with the first request
+pub struct Connection {
+    io: Option<Box<dyn Io>>,
+    client: Option<SendRequest<Bytes>>,
+}
+
+impl Connection {
+    pub fn new(io: Box<dyn Io>) -> Self {
+        Connection {
+            io: Some(io),
+            client: None,
+        }
+    }
+
+    /// Make the probe's request on the connection, returns the response body as it arrived
+    pub async fn exchange(
+        &mut self,
+        probe: &Probe,
+        measurement: &mut MeasureResponse,
+    ) -> Result<Downloaded, MeasureError> {
+        let send_start = Instant::now();
+        let response = timed(SEND_TIMEOUT, self.send(probe))
+            .await
+            .map_err(|e| e.during(Phase::Send, measurement))?;
+        let sent = Instant::now();
+        measurement.http_get_send_duration = sent - send_start;
+
+        let response = timed(FIRST_BYTE_TIMEOUT, async {
+            response.await.map_err(h2_error)
+        })
+        .await
+        .map_err(|e| e.during(Phase::FirstByte, measurement))?;
+        let mut transfer = Transfer::start();
+        measurement.ttfb_duration = transfer.first_byte() - sent;
+        record_head(measurement, probe, response.status(), response.headers());
+
+        let (head, mut body) = response.into_parts();
+        let buf = timed(BODY_TIMEOUT, async {
+            let mut buf = BytesMut::new();
+
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(h2_error)?;
+                transfer.record(chunk.len());
+                // let the target keep sending, the window is only for this one response
+                let _ = body.flow_control().release_capacity(chunk.len());
+                buf.extend_from_slice(&chunk);
+                check_body_size(buf.len())?;
+            }
+
+            Ok(buf)
+        })
+        .await
+        .map_err(|e| e.during(Phase::Body, measurement))?;
+
+        downloaded(measurement, sent, &transfer, &head.headers, buf.freeze())
+    }
+
+    /// Start the connection if it's new and send the request, with its body if it has one
+    async fn send(&mut self, probe: &Probe) -> Result<ResponseFuture, MeasureError> {
+        if let Some(io) = self.io.take() {
+            let (client, connection) = h2::client::handshake(io).await.map_err(h2_error)?;
+            tokio::spawn(async move {
+                let _ = connection.await;
+            });
+
+            self.client = Some(client);
+        }
+
+        let client = self.client.clone().expect("the connection was started");
+        let mut client = client.ready().await.map_err(h2_error)?;
+        let end_of_stream = probe.body.is_empty();
+        let (response, mut stream) = client
+            .send_request(super::request(probe)?, end_of_stream)
+            .map_err(h2_error)?;
+
+        if !end_of_stream {
+            stream
+                .send_data(probe.body.clone(), true)
+                .map_err(h2_error)?;
+        }
+
+        Ok(response)
+    }
+}
+
+fn h2_error(e: h2::Error) -> MeasureError {
+    if e.is_io() {
+        return MeasureError::Io(e.into_io().expect("is an io error"));
+    }
+
+    MeasureError::Protocol(e.to_string())
+}
@@ -0,0 +1,207 @@
+//! Http/3 over a quic connection of its own
+//!
+//! Quic runs the transport and tls handshakes together, so a measurement has a quic
+//! handshake duration in place of the tcp connect and tls handshake durations
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use h3::client::SendRequest;
+use h3_quinn::OpenStreams;
+use quinn::{
+    crypto::rustls::{HandshakeData, QuicClientConfig},
+    ClientConfig, Endpoint,
+};
+use url::Host;
+
+use super::{
+    check_body_size, downloaded, record_head, timed, tls::Handshake, transfer::Transfer,
+    Downloaded, Probe, BODY_TIMEOUT, FIRST_BYTE_TIMEOUT, HANDSHAKE_TIMEOUT, SEND_TIMEOUT,
+};
+use crate::{MeasureError, MeasureResponse, Phase, TlsInfo};
+
+/// A quic connection to the target, its http/3 control streams are opened along with
+/// the first request and the endpoint is closed when it's dropped
+pub struct Connection {
+    endpoint: Endpoint,
+    quic: quinn::Connection,
+    client: Option<SendRequest<OpenStreams, Bytes>>,
+    handshake: Handshake,
+}
+
+/// Open an endpoint and complete the quic handshake with the target
+pub async fn connect(
+    probe: &Probe,
+    addr: SocketAddr,
+    handshake: Handshake,
+    measurement: &mut MeasureResponse,
+) -> Result<Connection, MeasureError> {
+    let quic_start = Instant::now();
+    let quic = timed(HANDSHAKE_TIMEOUT, handshake_quic(probe, addr, &handshake));
+    let (endpoint, quic) = quic.await.map_err(|e| {
+        measurement.tls = handshake.presented();
+        e.during(Phase::QuicHandshake, measurement)
+    })?;
+    measurement.quic_handshake_duration = Some(quic_start.elapsed());
+    measurement.tls = Some(negotiated(&handshake, &quic));
+
+    Ok(Connection {
+        endpoint,
+        quic,
+        client: None,
+        handshake,
+    })
+}
+
+impl Connection {
+    /// Make the probe's request on the connection, returns the response body as it arrived
+    pub async fn exchange(
+        &mut self,
+        probe: &Probe,
+        measurement: &mut MeasureResponse,
+    ) -> Result<Downloaded, MeasureError> {
+        let send_start = Instant::now();
+        let mut stream = timed(SEND_TIMEOUT, async {
+            if self.client.is_none() {
+                let (mut driver, client) =
+                    h3::client::new(h3_quinn::Connection::new(self.quic.clone()))
+                        .await
+                        .map_err(h3_error)?;
+                tokio::spawn(async move {
+                    let _ = driver.wait_idle().await;
+                });
+
+                self.client = Some(client);
+            }
+
+            let client = self.client.as_mut().expect("the connection was started");
+            let mut stream = client
+                .send_request(super::request(probe)?)
+                .await
+                .map_err(h3_error)?;
+
+            if !probe.body.is_empty() {
+                stream
+                    .send_data(probe.body.clone())
+                    .await
+                    .map_err(h3_error)?;
+            }
+
+            stream.finish().await.map_err(h3_error)?;
+
+            Ok(stream)
+        })
+        .await
+        .map_err(|e| e.during(Phase::Send, measurement))?;
+        let sent = Instant::now();
+        measurement.http_get_send_duration = sent - send_start;
+
+        let response = timed(FIRST_BYTE_TIMEOUT, async {
+            stream.recv_response().await.map_err(h3_error)
+        })
+        .await
+        .map_err(|e| e.during(Phase::FirstByte, measurement))?;
+        let mut transfer = Transfer::start();
+        measurement.ttfb_duration = transfer.first_byte() - sent;
+        record_head(measurement, probe, response.status(), response.headers());
+
+        let body = timed(BODY_TIMEOUT, async {
+            let mut body = BytesMut::new();
+
+            while let Some(mut chunk) = stream.recv_data().await.map_err(h3_error)? {
+                transfer.record(chunk.remaining());
+
+                while chunk.has_remaining() {
+                    let part = chunk.chunk();
+                    body.extend_from_slice(part);
+                    chunk.advance(part.len());
+                }
+
+                check_body_size(body.len())?;
+            }
+
+            Ok(body)
+        })
+        .await
+        .map_err(|e| e.during(Phase::Body, measurement))?;
+
+        downloaded(
+            measurement,
+            sent,
+            &transfer,
+            response.headers(),
+            body.freeze(),
+        )
+    }
+
+    /// Every byte received on the connection so far
+    pub fn received(&self) -> u64 {
+        self.quic.stats().udp_rx.bytes
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.endpoint.close(0u32.into(), b"");
+    }
+}
+
+async fn handshake_quic(
+    probe: &Probe,
+    addr: SocketAddr,
+    handshake: &Handshake,
+) -> Result<(Endpoint, quinn::Connection), MeasureError> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+
+    let endpoint = Endpoint::client(local)?;
+    let tls = QuicClientConfig::try_from(handshake.config())
+        .map_err(|e| MeasureError::Internal(e.to_string()))?;
+
+    let server_name = match probe.url.host() {
+        Some(Host::Domain(domain)) => domain.to_string(),
+        _ => addr.ip().to_string(),
+    };
+
+    let connection = endpoint
+        .connect_with(ClientConfig::new(Arc::new(tls)), addr, &server_name)
+        .map_err(h3_error)?
+        .await
+        .map_err(h3_error)?;
+
+    Ok((endpoint, connection))
+}
+
+/// Quic always runs tls 1.3 and doesn't expose the cipher suite or the kind of handshake,
+/// a resumed one is told apart by the target not presenting a certificate
+fn negotiated(handshake: &Handshake, connection: &quinn::Connection) -> TlsInfo {
+    let alpn = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .map(|protocol| String::from_utf8_lossy(&protocol).into_owned());
+    let presented = handshake.presented();
+
+    TlsInfo {
+        version: Some("TLSv1_3".to_string()),
+        alpn,
+        resumed: presented.is_none(),
+        ..presented.unwrap_or_default()
+    }
+}
+
+fn h3_error(e: impl fmt::Display) -> MeasureError {
+    MeasureError::Protocol(e.to_string())
+}
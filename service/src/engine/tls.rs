@@ -0,0 +1,268 @@
+//! The tls side of the engine, the client configs and what a handshake negotiated
+//!
+//! Every connection gets a verifier of its own wrapping the shared webpki one, so the
+//! certificate and ocsp staple of a handshake are known even when the certificate is rejected,
+//! and a session store of its own, so only a connection asked to resume it can
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio_rustls::{
+    rustls::{
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            Resumption, WebPkiServerVerifier,
+        },
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, CommonState, DigitallySignedStruct, Error, HandshakeKind, RootCertStore,
+        SignatureScheme,
+    },
+    TlsConnector,
+};
+use x509_parser::{
+    certificate::X509Certificate, extensions::GeneralName, prelude::FromDer, time::ASN1Time,
+};
+
+use crate::{CertificateInfo, HttpProtocol, TlsInfo};
+
+/// The tls client of a single connection
+pub struct Handshake {
+    config: Arc<ClientConfig>,
+    verifier: Arc<Recorder>,
+}
+
+impl Handshake {
+    /// A full handshake, its session is kept for [`Handshake::resume`]
+    pub fn new(protocol: HttpProtocol) -> Self {
+        let verifier = Arc::new(Recorder {
+            inner: webpki_verifier(),
+            leaf: Mutex::new(None),
+            ocsp_stapled: AtomicBool::new(false),
+        });
+
+        let mut config = base_config(protocol).as_ref().clone();
+        config.resumption = Resumption::default();
+        config
+            .dangerous()
+            .set_certificate_verifier(verifier.clone());
+
+        Handshake {
+            config: Arc::new(config),
+            verifier,
+        }
+    }
+
+    /// A handshake for a new connection offering the session of this one, once it's done
+    ///
+    /// Rustls only resumes a session with the verifier it was verified with, so the recorder
+    /// is shared and starts over
+    pub fn resume(&self) -> Self {
+        self.verifier.reset();
+
+        Handshake {
+            config: self.config.clone(),
+            verifier: self.verifier.clone(),
+        }
+    }
+
+    pub fn config(&self) -> Arc<ClientConfig> {
+        self.config.clone()
+    }
+
+    pub fn connector(&self) -> TlsConnector {
+        TlsConnector::from(self.config.clone())
+    }
+
+    /// What the target presented in the handshake, if it got as far as its certificate
+    pub fn presented(&self) -> Option<TlsInfo> {
+        let leaf = self.verifier.leaf.lock().unwrap_or_else(|e| e.into_inner());
+
+        Some(TlsInfo {
+            ocsp_stapled: self.verifier.ocsp_stapled.load(Ordering::Relaxed),
+            certificate: certificate_info(leaf.as_ref()?),
+            ..Default::default()
+        })
+    }
+
+    /// What the completed handshake of a tls connection negotiated
+    pub fn negotiated(&self, connection: &CommonState) -> TlsInfo {
+        TlsInfo {
+            version: connection
+                .protocol_version()
+                .map(|version| format!("{:?}", version)),
+            cipher_suite: connection
+                .negotiated_cipher_suite()
+                .map(|suite| format!("{:?}", suite.suite())),
+            alpn: connection
+                .alpn_protocol()
+                .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+            resumed: connection.handshake_kind() == Some(HandshakeKind::Resumed),
+            ..self.presented().unwrap_or_default()
+        }
+    }
+}
+
+/// Verifies certificates with webpki, recording the leaf certificate and whether an ocsp
+/// response was stapled before the verdict
+#[derive(Debug)]
+struct Recorder {
+    inner: Arc<WebPkiServerVerifier>,
+    leaf: Mutex<Option<CertificateDer<'static>>>,
+    ocsp_stapled: AtomicBool,
+}
+
+impl Recorder {
+    fn reset(&self) {
+        *self.leaf.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.ocsp_stapled.store(false, Ordering::Relaxed);
+    }
+}
+
+impl ServerCertVerifier for Recorder {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        *self.leaf.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some(end_entity.clone().into_owned());
+        self.ocsp_stapled
+            .store(!ocsp_response.is_empty(), Ordering::Relaxed);
+
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn certificate_info(der: &CertificateDer<'_>) -> Option<CertificateInfo> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+    let sans = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                GeneralName::IPAddress(ip) => ip_address(ip).map(|ip| ip.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Some(CertificateInfo {
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        sans,
+        not_before: system_time(cert.validity().not_before),
+        not_after: system_time(cert.validity().not_after),
+    })
+}
+
+fn ip_address(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).into()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).into()),
+        _ => None,
+    }
+}
+
+fn system_time(time: ASN1Time) -> SystemTime {
+    let secs = time.timestamp();
+
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+/// The configs are shared between connections, building the root store is not free
+fn base_config(protocol: HttpProtocol) -> &'static Arc<ClientConfig> {
+    static H1: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    static H2: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    static H3: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    match protocol {
+        HttpProtocol::H1 => H1.get_or_init(|| Arc::new(config(&[b"http/1.1"]))),
+        // a target without h2 is measured over http/1.1, the measurement records which it was
+        HttpProtocol::H2 => H2.get_or_init(|| Arc::new(config(&[b"h2", b"http/1.1"]))),
+        HttpProtocol::H3 => H3.get_or_init(|| Arc::new(config(&[b"h3"]))),
+    }
+}
+
+/// The tls config of dns over tls and https servers, hickory offers the alpn protocol itself
+pub fn dns_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_root_certificates(roots())
+        .with_no_client_auth()
+}
+
+/// A tls config offering the alpn protocols, in order of preference
+fn config(alpn: &[&[u8]]) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(roots())
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    // sessions are only shared between the connections of a handshake and its resumptions
+    config.resumption = Resumption::disabled();
+
+    config
+}
+
+fn roots() -> Arc<RootCertStore> {
+    static ROOTS: OnceLock<Arc<RootCertStore>> = OnceLock::new();
+
+    ROOTS
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+            Arc::new(roots)
+        })
+        .clone()
+}
+
+fn webpki_verifier() -> Arc<WebPkiServerVerifier> {
+    static VERIFIER: OnceLock<Arc<WebPkiServerVerifier>> = OnceLock::new();
+
+    VERIFIER
+        .get_or_init(|| {
+            WebPkiServerVerifier::builder(roots())
+                .build()
+                .expect("the root store isn't empty")
+        })
+        .clone()
+}
@@ -0,0 +1,151 @@
+//! Tracking the bytes of a response as they arrive, for its throughput and wire size
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The window the peak throughput is measured over
+const PEAK_WINDOW: Duration = Duration::from_millis(100);
+
+/// The arrival of a response, from its first byte to its last
+#[derive(Debug)]
+pub struct Transfer {
+    first_byte: Instant,
+    last_byte: Instant,
+    window_start: Instant,
+    window_bytes: u64,
+    /// The fastest complete window so far, in bytes per second
+    peak: Option<f64>,
+}
+
+impl Transfer {
+    /// Start tracking a response whose first byte arrived now
+    pub fn start() -> Self {
+        let now = Instant::now();
+
+        Transfer {
+            first_byte: now,
+            last_byte: now,
+            window_start: now,
+            window_bytes: 0,
+            peak: None,
+        }
+    }
+
+    pub fn first_byte(&self) -> Instant {
+        self.first_byte
+    }
+
+    pub fn last_byte(&self) -> Instant {
+        self.last_byte
+    }
+
+    /// Record `n` bytes arriving now
+    pub fn record(&mut self, n: usize) {
+        let now = Instant::now();
+        let window = now - self.window_start;
+
+        if window >= PEAK_WINDOW {
+            let rate = self.window_bytes as f64 / window.as_secs_f64();
+            self.peak = Some(self.peak.map_or(rate, |peak| peak.max(rate)));
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+
+        self.window_bytes += n as u64;
+        self.last_byte = now;
+    }
+
+    /// The average and peak throughput of a body of `size` bytes, in bytes per second
+    ///
+    /// A body that arrived all at once has no throughput, one that arrived within a single
+    /// window peaks at its average
+    pub fn throughput(&self, size: u64) -> Option<(f64, f64)> {
+        let duration = self.last_byte - self.first_byte;
+
+        if duration.is_zero() {
+            return None;
+        }
+
+        let average = size as f64 / duration.as_secs_f64();
+
+        Some((average, self.peak.map_or(average, |peak| peak.max(average))))
+    }
+}
+
+/// A connection counting the bytes read from it, the count is shared so it can be read
+/// while the connection is owned by another task
+pub struct Counted<T> {
+    inner: T,
+    received: Arc<AtomicU64>,
+}
+
+impl<T> Counted<T> {
+    pub fn new(inner: T) -> Self {
+        Counted {
+            inner,
+            received: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn received(&self) -> Arc<AtomicU64> {
+        self.received.clone()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if poll.is_ready() {
+            self.received
+                .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        }
+
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
@@ -0,0 +1,104 @@
+//! The on-disk log of scheduled measurements
+//!
+//! Entries are appended to a json lines file, once it grows past its size limit it replaces
+//! the previous file, so at most two files' worth of history is kept
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use measure::{HistoryEntry, MeasureError};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+pub struct History {
+    path: PathBuf,
+    /// Where the log is moved once it's full
+    previous: PathBuf,
+    max_bytes: u64,
+    /// Appends and rotations happen one at a time
+    lock: Mutex<()>,
+}
+
+impl History {
+    pub fn new(path: PathBuf, max_bytes: u64) -> Self {
+        let mut previous = path.clone().into_os_string();
+        previous.push(".1");
+
+        History {
+            path,
+            previous: previous.into(),
+            max_bytes,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn append(&self, entry: &HistoryEntry) -> Result<(), MeasureError> {
+        let mut line =
+            serde_json::to_vec(entry).map_err(|e| MeasureError::Internal(e.to_string()))?;
+        line.push(b'\n');
+
+        let _lock = self.lock.lock().await;
+
+        let size = match fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            fs::rename(&self.path, &self.previous).await?;
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+
+        Ok(())
+    }
+
+    /// The entries for the target, or every target, recorded at or after `since`, oldest first
+    pub async fn query(
+        &self,
+        target: Option<&str>,
+        since: Option<SystemTime>,
+    ) -> Result<Vec<HistoryEntry>, MeasureError> {
+        let _lock = self.lock.lock().await;
+
+        let mut entries = Vec::new();
+
+        for path in [&self.previous, &self.path] {
+            for entry in read(path).await? {
+                if target.is_some_and(|target| entry.target != target) {
+                    continue;
+                }
+
+                if since.is_some_and(|since| entry.time < since) {
+                    continue;
+                }
+
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Read the entries of a log file, a line cut short by a crash is skipped
+async fn read(path: &Path) -> Result<Vec<HistoryEntry>, MeasureError> {
+    let contents = match fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
@@ -0,0 +1,130 @@
+//! What the service knows about itself and the host it runs on, for the info and health routes
+
+use std::{
+    fs,
+    net::{SocketAddr, UdpSocket},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use http::Method;
+use measure::{
+    engine::{self, Probe},
+    HealthResponse, ServiceInfo,
+};
+
+/// Answers with the address the request came from
+const PUBLIC_IP_URL: &str = "https://checkip.amazonaws.com/";
+const PUBLIC_IP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Well known public resolvers, only used to pick the outbound interface, nothing is sent to them
+const ROUTE_PROBES: &[&str] = &["8.8.8.8:53", "[2001:4860:4860::8888]:53"];
+
+/// The measurement routes of the service
+const MEASUREMENTS: &[&str] = &["ttfb", "duration", "batch", "stream", "jobs", "dns"];
+
+pub struct Info {
+    started: Instant,
+    region: Option<String>,
+    hostname: Option<String>,
+    kernel: Option<String>,
+    cpu_model: Option<String>,
+    cpus: usize,
+    private_ips: Vec<String>,
+    public_ip: OnceLock<String>,
+}
+
+impl Info {
+    /// Collect the host metadata, the public ip is looked up separately by [`Info::lookup_public_ip`]
+    pub fn new(region: Option<String>) -> Self {
+        Info {
+            started: Instant::now(),
+            region,
+            hostname: read_proc("/proc/sys/kernel/hostname"),
+            kernel: read_proc("/proc/sys/kernel/osrelease"),
+            cpu_model: cpu_model(),
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            private_ips: private_ips(),
+            public_ip: OnceLock::new(),
+        }
+    }
+
+    /// Look up the address the host reaches the internet from, it's left unset on failure
+    pub async fn lookup_public_ip(&self) {
+        let lookup = async {
+            let probe = Probe::new(PUBLIC_IP_URL, Method::GET).ok()?;
+            let (measurement, body) = engine::fetch(&probe).await.ok()?;
+
+            if measurement.status != Some(200) {
+                return None;
+            }
+
+            String::from_utf8(body.to_vec()).ok()
+        };
+
+        match tokio::time::timeout(PUBLIC_IP_TIMEOUT, lookup).await {
+            Ok(Some(ip)) => {
+                let _ = self.public_ip.set(ip.trim().to_string());
+            }
+            _ => println!("Failed to look up the public ip of the service"),
+        }
+    }
+
+    pub fn health(&self) -> HealthResponse {
+        HealthResponse {
+            status: "ok".to_string(),
+            uptime: self.started.elapsed(),
+        }
+    }
+
+    pub fn service_info(&self) -> ServiceInfo {
+        ServiceInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            commit: option_env!("MEASURE_GIT_COMMIT").map(str::to_string),
+            uptime: self.started.elapsed(),
+            region: self.region.clone(),
+            hostname: self.hostname.clone(),
+            public_ip: self.public_ip.get().cloned(),
+            private_ips: self.private_ips.clone(),
+            kernel: self.kernel.clone(),
+            cpu_model: self.cpu_model.clone(),
+            cpus: self.cpus,
+            measurements: MEASUREMENTS.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+}
+
+fn read_proc(path: &str) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim().to_string())
+}
+
+fn cpu_model() -> Option<String> {
+    read_proc("/proc/cpuinfo")?
+        .lines()
+        .find(|line| line.starts_with("model name"))
+        .and_then(|line| line.split_once(':'))
+        .map(|(_, model)| model.trim().to_string())
+}
+
+/// The local addresses of the interfaces outbound traffic leaves from, connecting a udp socket
+/// only picks a route
+fn private_ips() -> Vec<String> {
+    ROUTE_PROBES
+        .iter()
+        .filter_map(|probe| {
+            let remote: SocketAddr = probe.parse().ok()?;
+            let local: SocketAddr = if remote.is_ipv4() {
+                "0.0.0.0:0".parse().ok()?
+            } else {
+                "[::]:0".parse().ok()?
+            };
+
+            let socket = UdpSocket::bind(local).ok()?;
+            socket.connect(remote).ok()?;
+
+            Some(socket.local_addr().ok()?.ip().to_string())
+        })
+        .collect()
+}
@@ -0,0 +1,268 @@
+//! Batches run in the background, kept in memory until they expire
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use measure::{JobResponse, JobStatus, MeasureError, MeasureResponse};
+use tokio::task::AbortHandle;
+
+struct Job {
+    response: JobResponse,
+    task: Option<AbortHandle>,
+}
+
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Job>>,
+    /// How long a job is kept after it finished
+    ttl: Duration,
+    /// The most jobs that can be running at once
+    max_running: usize,
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration, max_running: usize) -> Self {
+        JobStore {
+            jobs: Mutex::new(HashMap::new()),
+            ttl,
+            max_running,
+        }
+    }
+
+    /// Add a running job taking `times` samples, returns its id, refused if as many jobs as
+    /// allowed are already running
+    pub fn insert(&self, times: usize) -> Result<String, MeasureError> {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let mut jobs = self.lock();
+
+        let running = jobs
+            .values()
+            .filter(|job| job.response.status == JobStatus::Running)
+            .count();
+        if running >= self.max_running {
+            return Err(MeasureError::TooManyRequests(format!(
+                "{} jobs are already running",
+                running
+            )));
+        }
+
+        jobs.insert(
+            id.clone(),
+            Job {
+                response: JobResponse {
+                    id: id.clone(),
+                    status: JobStatus::Running,
+                    created: SystemTime::now(),
+                    finished: None,
+                    times,
+                    results: Vec::new(),
+                    error: None,
+                },
+                task: None,
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Set the task running the job, so it can be cancelled
+    pub fn set_task(&self, id: &str, task: AbortHandle) {
+        if let Some(job) = self.lock().get_mut(id) {
+            job.task = Some(task);
+        }
+    }
+
+    /// Record a sample of the job, unless it was cancelled
+    pub fn push(&self, id: &str, sample: MeasureResponse) {
+        if let Some(job) = running(&mut self.lock(), id) {
+            job.response.results.push(sample);
+        }
+    }
+
+    /// Mark the job completed, or failed with the error, unless it was cancelled
+    pub fn finish(&self, id: &str, result: Result<(), MeasureError>) {
+        if let Some(job) = running(&mut self.lock(), id) {
+            job.response.status = match result {
+                Ok(()) => JobStatus::Completed,
+                Err(e) => {
+                    job.response.error = Some(e.into());
+                    JobStatus::Failed
+                }
+            };
+            job.response.finished = Some(SystemTime::now());
+            job.task = None;
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<JobResponse, MeasureError> {
+        self.lock()
+            .get(id)
+            .map(|job| job.response.clone())
+            .ok_or_else(|| not_found(id))
+    }
+
+    /// Cancel a running job, keeping the samples it took, a finished job is removed
+    pub fn cancel(&self, id: &str) -> Result<JobResponse, MeasureError> {
+        let mut jobs = self.lock();
+        let job = jobs.get_mut(id).ok_or_else(|| not_found(id))?;
+
+        if job.response.status != JobStatus::Running {
+            return Ok(jobs.remove(id).expect("job exists").response);
+        }
+
+        if let Some(task) = job.task.take() {
+            task.abort();
+        }
+
+        job.response.status = JobStatus::Cancelled;
+        job.response.finished = Some(SystemTime::now());
+
+        Ok(job.response.clone())
+    }
+
+    /// Remove the jobs that finished longer than the ttl ago
+    pub fn expire(&self) {
+        let now = SystemTime::now();
+
+        self.lock().retain(|_, job| match job.response.finished {
+            Some(finished) => now
+                .duration_since(finished)
+                .map_or(true, |age| age < self.ttl),
+            None => true,
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Job>> {
+        // a panic while holding the lock can't leave the map half updated
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The job if it's still running, its task can get past its last await before a cancel
+/// aborts it
+fn running<'a>(jobs: &'a mut HashMap<String, Job>, id: &str) -> Option<&'a mut Job> {
+    jobs.get_mut(id)
+        .filter(|job| job.response.status == JobStatus::Running)
+}
+
+fn not_found(id: &str) -> MeasureError {
+    MeasureError::NotFound(format!("no job with id {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> JobStore {
+        JobStore::new(Duration::from_secs(60), 2)
+    }
+
+    fn sample(status: u16) -> MeasureResponse {
+        MeasureResponse {
+            status: Some(status),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn jobs_complete_with_their_samples() {
+        let jobs = store();
+        let id = jobs.insert(2).unwrap();
+
+        jobs.push(&id, sample(200));
+        jobs.push(&id, sample(204));
+        jobs.finish(&id, Ok(()));
+
+        let job = jobs.get(&id).unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!(job.times, 2);
+        assert_eq!(job.results.len(), 2);
+        assert!(job.finished.is_some());
+        assert!(job.error.is_none());
+    }
+
+    #[test]
+    fn jobs_fail_with_the_error() {
+        let jobs = store();
+        let id = jobs.insert(1).unwrap();
+
+        jobs.finish(&id, Err(MeasureError::Protocol("broken".to_string())));
+
+        let job = jobs.get(&id).unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert!(job.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_running_job_aborts_it() {
+        let jobs = store();
+        let id = jobs.insert(2).unwrap();
+        let task = tokio::spawn(std::future::pending::<()>());
+        jobs.set_task(&id, task.abort_handle());
+        jobs.push(&id, sample(200));
+
+        let job = jobs.cancel(&id).unwrap();
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert_eq!(job.results.len(), 1);
+        assert!(task.await.unwrap_err().is_cancelled());
+
+        // still kept until it expires
+        assert_eq!(jobs.get(&id).unwrap().status, JobStatus::Cancelled);
+    }
+
+    #[test]
+    fn cancelling_a_finished_job_removes_it() {
+        let jobs = store();
+        let id = jobs.insert(1).unwrap();
+        jobs.finish(&id, Ok(()));
+
+        assert_eq!(jobs.cancel(&id).unwrap().status, JobStatus::Completed);
+        assert!(matches!(jobs.get(&id), Err(MeasureError::NotFound(_))));
+        assert!(matches!(jobs.cancel(&id), Err(MeasureError::NotFound(_))));
+    }
+
+    #[test]
+    fn cancelled_jobs_stay_cancelled() {
+        let jobs = store();
+        let id = jobs.insert(2).unwrap();
+        let cancelled = jobs.cancel(&id).unwrap();
+
+        jobs.push(&id, sample(200));
+        jobs.finish(&id, Ok(()));
+
+        let job = jobs.get(&id).unwrap();
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert!(job.results.is_empty());
+        assert_eq!(job.finished, cancelled.finished);
+    }
+
+    #[test]
+    fn running_jobs_are_limited() {
+        let jobs = store();
+        let first = jobs.insert(1).unwrap();
+        jobs.insert(1).unwrap();
+
+        assert!(matches!(
+            jobs.insert(1),
+            Err(MeasureError::TooManyRequests(_))
+        ));
+
+        jobs.finish(&first, Ok(()));
+        assert!(jobs.insert(1).is_ok());
+    }
+
+    #[test]
+    fn finished_jobs_expire() {
+        let jobs = JobStore::new(Duration::ZERO, 2);
+        let running = jobs.insert(1).unwrap();
+        let finished = jobs.insert(1).unwrap();
+        jobs.finish(&finished, Ok(()));
+
+        jobs.expire();
+
+        assert!(jobs.get(&running).is_ok());
+        assert!(jobs.get(&finished).is_err());
+    }
+}
@@ -0,0 +1,7 @@
+pub mod auth;
+pub mod engine;
+pub mod policy;
+mod types;
+pub mod verify;
+
+pub use types::*;
@@ -1,102 +1,856 @@
-mod types;
+mod config;
+mod history;
+mod info;
+mod jobs;
+mod metrics;
+mod schedule;
 
-use std::time::{Duration, Instant};
+use std::{
+    borrow::Cow,
+    convert::Infallible,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use axum::{routing::post, Json, Router};
-use measure::{MeasureDurationRequest, MeasureError, MeasureRequest, MeasureResponse};
-use reqwest::{Client, Method};
-use serde_json::Value;
-use tokio::task;
-use ttfb::ttfb;
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Path, Query, Request, State,
+    },
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use clap::Parser;
+use config::{CliArgs, Limits, TlsConfig};
+use history::History;
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Method, StatusCode,
+};
+use info::Info;
+use jobs::JobStore;
+use measure::{
+    auth::KeyStore,
+    engine::{self, dns, Probe},
+    policy::TargetPolicy,
+    AddressFamily, AddressMeasurement, DnsRequest, DnsResponse, ErrorResponse, HealthResponse,
+    HistoryEntry, JobResponse, MeasureBatchRequest, MeasureDurationRequest, MeasureError,
+    MeasureRequest, MeasureResponse, RedirectPolicy, ServiceInfo, Warmup,
+};
+use metrics::Metrics;
+use rand::Rng;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{sync::mpsc, task::JoinSet};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
+
+/// The state shared by the handlers
+#[derive(Clone)]
+struct AppState {
+    policy: Arc<TargetPolicy>,
+    limits: Limits,
+    metrics: Arc<Metrics>,
+    info: Arc<Info>,
+    jobs: Arc<JobStore>,
+    history: Arc<History>,
+}
 
 #[tokio::main]
-async fn main() {
-    let app = Router::new()
+async fn main() -> anyhow::Result<()> {
+    let config = CliArgs::parse().config()?;
+
+    let mut app = Router::new()
         .route("/ttfb", post(measure_ttfb))
-        .route("/duration", post(measure_duration));
+        .route("/duration", post(measure_duration))
+        .route("/batch", post(measure_batch))
+        .route("/dns", post(measure_dns))
+        .route("/stream", post(measure_stream))
+        .route("/jobs", post(create_job))
+        .route("/jobs/:id", get(get_job).delete(cancel_job))
+        .route("/metrics", get(metrics))
+        .route("/info", get(info))
+        .route("/history", get(history));
+
+    match config.auth_keys {
+        Some(ref path) => {
+            let keys = KeyStore::load(path)
+                .with_context(|| format!("failed to load auth keys {}", path.display()))?;
+
+            app = app.route_layer(middleware::from_fn_with_state(Arc::new(keys), authenticate));
+        }
+        None => println!("No auth keys configured, the service will accept any request"),
+    }
+
+    let info = Arc::new(Info::new(config.region.clone()));
+    tokio::spawn({
+        let info = info.clone();
+        async move { info.lookup_public_ip().await }
+    });
+
+    let jobs = Arc::new(JobStore::new(config.job_ttl, config.limits.max_jobs));
+    tokio::spawn({
+        let jobs = jobs.clone();
+        async move {
+            let mut interval = tokio::time::interval(JOB_EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                jobs.expire();
+            }
+        }
+    });
+
+    let policy = Arc::new(config.policy);
+    let scheduled = config
+        .schedules
+        .iter()
+        .filter_map(|schedule| url::Url::parse(&schedule.request.request.target).ok())
+        .filter_map(|url| url.host_str().map(str::to_string))
+        .collect();
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
+    let app_state = AppState {
+        policy: policy.clone(),
+        limits: config.limits,
+        metrics: Arc::new(Metrics::new(
+            policy,
+            scheduled,
+            config.limits.max_metric_targets,
+        )?),
+        info,
+        jobs,
+        history: Arc::new(History::new(config.history, config.history_max_bytes)),
+    };
+
+    for schedule in config.schedules {
+        schedule.spawn(app_state.clone());
+    }
+
+    // the health route stays open for load balancers
+    let app = app.route("/health", get(health)).with_state(app_state);
+
+    let tls = match config.tls {
+        Some(ref tls) => Some(rustls_config(tls).await?),
+        None => None,
+    };
+
+    let mut servers = JoinSet::new();
+
+    for addr in config.listen {
+        let listener = bind(addr).with_context(|| format!("failed to bind to {}", addr))?;
+        let service = app.clone().into_make_service();
+
+        match tls {
+            Some(ref tls) => {
+                println!("Listening on https://{}", addr);
+                servers.spawn(axum_server::from_tcp_rustls(listener, tls.clone()).serve(service));
+            }
+            None => {
+                println!("Listening on http://{}", addr);
+                servers.spawn(axum_server::from_tcp(listener).serve(service));
+            }
+        }
+    }
+
+    // the service runs until any of its listeners fail
+    while let Some(res) = servers.join_next().await {
+        res??;
+    }
+
+    Ok(())
+}
+
+/// Bind a listener, ipv6 listeners only accept ipv6 so they can share a port with an ipv4 one
+fn bind(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+async fn rustls_config(tls: &TlsConfig) -> anyhow::Result<RustlsConfig> {
+    match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => RustlsConfig::from_pem_file(cert, key)
+            .await
+            .context("failed to load the tls certificate and key"),
+        _ => {
+            println!("Generating a self signed certificate");
+
+            let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+
+            Ok(RustlsConfig::from_pem(
+                certified.cert.pem().into_bytes(),
+                certified.key_pair.serialize_pem().into_bytes(),
+            )
+            .await?)
+        }
+    }
+}
+
+/// How often finished jobs past their ttl are removed
+const JOB_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The largest request body the service reads to verify a signature
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Reject requests without a valid bearer token or signature, the body is buffered
+/// so a signature over it can be checked before the handler sees it
+async fn authenticate(
+    State(keys): State<Arc<KeyStore>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, MeasureError> {
+    let (parts, body) = request.into_parts();
+
+    let authorization = parts
+        .headers
+        .get(AUTHORIZATION)
+        .ok_or_else(|| MeasureError::Unauthorized("Missing authorization header".to_string()))?
+        .to_str()
+        .map_err(|_| MeasureError::Unauthorized("Malformed authorization".to_string()))?;
+
+    let body = axum::body::to_bytes(body, MAX_BODY_SIZE)
         .await
-        .expect("failed to bind to port 3000");
+        .map_err(|e| MeasureError::BadRequest(format!("Failed to read body: {}", e)))?;
 
-    println!("Listening on 3000");
+    keys.verify(
+        authorization,
+        parts.method.as_str(),
+        parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path| path.as_str()),
+        &body,
+    )?;
 
-    let _ = axum::serve(listener, app).await;
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
 }
 
 async fn measure_ttfb(
-    Json(target): Json<MeasureRequest>,
+    State(state): State<AppState>,
+    payload: Result<Json<MeasureRequest>, JsonRejection>,
 ) -> Result<Json<MeasureResponse>, MeasureError> {
-    let target = target.target;
-    println!("target_request_url: {:?}", target);
+    let _in_flight = state.metrics.request("ttfb");
+    let Json(target) = payload?;
+    println!("target_request_url: {:?}", target.target);
 
-    let handle = task::spawn_blocking(move || {
-        ttfb(&target, true).map(|outcome| {
-            let response: MeasureResponse = outcome.into();
-            Json(response)
-        })
+    state.metrics.result(ttfb(&state, &target).await).map(Json)
+}
+
+async fn measure_duration(
+    State(state): State<AppState>,
+    payload: Result<Json<MeasureDurationRequest>, JsonRejection>,
+) -> Result<Json<MeasureResponse>, MeasureError> {
+    let _in_flight = state.metrics.request("duration");
+    let Json(target) = payload?;
+
+    state
+        .metrics
+        .result(duration(&state, &target).await)
+        .map(Json)
+}
+
+async fn measure_batch(
+    State(state): State<AppState>,
+    payload: Result<Json<MeasureBatchRequest>, JsonRejection>,
+) -> Result<Json<Vec<MeasureResponse>>, MeasureError> {
+    let _in_flight = state.metrics.request("batch");
+    let Json(batch) = payload?;
+    check_batch(&state.limits, &batch)?;
+
+    println!(
+        "batch target_request_url: {:?}, times: {}",
+        batch.request.request.target, batch.times
+    );
+
+    let mut samples = Vec::new();
+
+    state
+        .metrics
+        .result(sample(&state, &batch, |s| samples.push(s)).await)?;
+
+    Ok(Json(samples))
+}
+
+/// Look a name up with the resolver, without making a request to it
+async fn measure_dns(
+    State(state): State<AppState>,
+    payload: Result<Json<DnsRequest>, JsonRejection>,
+) -> Result<Json<DnsResponse>, MeasureError> {
+    let _in_flight = state.metrics.request("dns");
+    let Json(request) = payload?;
+    println!("dns lookup: {:?}", request.name);
+
+    state.metrics.result(dns(&state, &request).await).map(Json)
+}
+
+/// Stream the samples of a batch as server sent events as they're taken, `sample` events carry
+/// a measurement and the stream ends with a `done` event, or an `error` event if the batch failed
+async fn measure_stream(
+    State(state): State<AppState>,
+    payload: Result<Json<MeasureBatchRequest>, JsonRejection>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, MeasureError> {
+    let Json(batch) = payload?;
+
+    // refuse invalid targets before the stream starts
+    check_batch(&state.limits, &batch)?;
+    duration_probe(&state, &batch.request)?;
+
+    println!(
+        "stream target_request_url: {:?}, times: {}",
+        batch.request.request.target, batch.times
+    );
+
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let _in_flight = state.metrics.request("stream");
+
+        let sampling = sample(&state, &batch, |s| {
+            let _ = tx.send(json_event("sample", &s));
+        });
+
+        // stop sampling once the client goes away
+        let result = tokio::select! {
+            result = sampling => result,
+            _ = tx.closed() => return,
+        };
+
+        let _ = tx.send(match state.metrics.result(result) {
+            Ok(()) => Event::default().event("done").data(""),
+            Err(e) => json_event("error", &ErrorResponse::from(e)),
+        });
+    });
+
+    Ok(Sse::new(UnboundedReceiverStream::new(rx).map(Ok)).keep_alive(KeepAlive::default()))
+}
+
+fn json_event(name: &str, data: &impl serde::Serialize) -> Event {
+    Event::default()
+        .event(name)
+        .json_data(data)
+        .expect("responses serialize to json")
+}
+
+/// Start running a batch in the background, its samples are collected in the job
+async fn create_job(
+    State(state): State<AppState>,
+    payload: Result<Json<MeasureBatchRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<JobResponse>), MeasureError> {
+    let Json(batch) = payload?;
+
+    // refuse invalid targets now rather than failing the job
+    check_batch(&state.limits, &batch)?;
+    duration_probe(&state, &batch.request)?;
+
+    let id = state.jobs.insert(batch.times)?;
+    println!(
+        "job {} target_request_url: {:?}, times: {}",
+        id, batch.request.request.target, batch.times
+    );
+
+    let task = tokio::spawn({
+        let state = state.clone();
+        let id = id.clone();
+        async move {
+            let result = sample(&state, &batch, |s| state.jobs.push(&id, s)).await;
+
+            state.jobs.finish(&id, state.metrics.result(result));
+        }
     });
+    state.jobs.set_task(&id, task.abort_handle());
+
+    Ok((StatusCode::ACCEPTED, Json(state.jobs.get(&id)?)))
+}
+
+/// The status of a job and the samples taken so far
+async fn get_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<JobResponse>, MeasureError> {
+    Ok(Json(state.jobs.get(&id)?))
+}
+
+/// Cancel a running job, or remove a finished one
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<JobResponse>, MeasureError> {
+    Ok(Json(state.jobs.cancel(&id)?))
+}
+
+/// The service's metrics in the prometheus text format
+async fn metrics(State(state): State<AppState>) -> Result<impl IntoResponse, MeasureError> {
+    let body = state
+        .metrics
+        .encode()
+        .map_err(|e| MeasureError::Internal(e.to_string()))?;
+
+    Ok(([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], body))
+}
+
+async fn health(State(state): State<AppState>) -> Json<HealthResponse> {
+    Json(state.info.health())
+}
+
+/// The version of the service and what it knows about its host
+async fn info(State(state): State<AppState>) -> Json<ServiceInfo> {
+    Json(state.info.service_info())
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// Only the entries for this target url
+    target: Option<String>,
+    /// Only the entries recorded at or after this unix timestamp, in seconds
+    since: Option<u64>,
+}
+
+/// The scheduled measurements recorded by the service, oldest first
+async fn history(
+    State(state): State<AppState>,
+    query: Result<Query<HistoryQuery>, QueryRejection>,
+) -> Result<Json<Vec<HistoryEntry>>, MeasureError> {
+    let Query(query) = query?;
+    let since = query
+        .since
+        .map(|since| SystemTime::UNIX_EPOCH + Duration::from_secs(since));
+
+    Ok(Json(
+        state.history.query(query.target.as_deref(), since).await?,
+    ))
+}
+
+/// Measure a single request
+async fn ttfb(state: &AppState, target: &MeasureRequest) -> Result<MeasureResponse, MeasureError> {
+    let probe = probe(state, target)?;
+
+    let measure = |probe: Probe| async move { run(state, &probe, target.redirect).await };
+
+    if target.fan_out {
+        return each_address(&probe, state.limits.max_addresses, measure).await;
+    }
 
-    match handle.await {
-        Ok(result) => result.map_err(MeasureError::from),
-        Err(e) => Err(MeasureError::from(e)),
+    if target.dual_stack {
+        return dual_stack(&probe, measure).await;
     }
+
+    run(state, &probe, target.redirect).await
 }
 
-async fn measure_duration(
-    Json(target): Json<MeasureDurationRequest>,
-) -> Result<Json<MeasureResponse>, MeasureError> {
-    let client = Client::new();
+/// Look up a name, the name and the resolver's server are checked against the policy
+async fn dns(state: &AppState, request: &DnsRequest) -> Result<DnsResponse, MeasureError> {
+    let resolver = dns::Resolver::new(&request.resolver)?;
+
+    state.policy.check_host(&request.name)?;
+
+    if let Some(server) = resolver.server() {
+        state.policy.check_ip(server.ip())?;
+    }
+
+    dns::lookup(&resolver, &request.name, &request.record_types).await
+}
+
+/// Refuse batches taking more samples or pausing longer than the limits allow
+fn check_batch(limits: &Limits, batch: &MeasureBatchRequest) -> Result<(), MeasureError> {
+    if batch.times > limits.max_times {
+        return Err(MeasureError::BadRequest(format!(
+            "A batch can take at most {} samples",
+            limits.max_times
+        )));
+    }
+
+    if batch.delay > limits.max_delay {
+        return Err(MeasureError::BadRequest(format!(
+            "The delay between samples can be at most {}ms",
+            limits.max_delay
+        )));
+    }
+
+    if batch
+        .jitter
+        .is_some_and(|jitter| jitter > limits.max_jitter)
+    {
+        return Err(MeasureError::BadRequest(format!(
+            "The jitter can be at most {}ms",
+            limits.max_jitter
+        )));
+    }
+
+    Ok(())
+}
+
+/// Take the samples of a batch, pausing between them, each sample is recorded as it's taken
+async fn sample(
+    state: &AppState,
+    batch: &MeasureBatchRequest,
+    mut record: impl FnMut(MeasureResponse),
+) -> Result<(), MeasureError> {
+    for i in 0..batch.times {
+        if i > 0 {
+            let jitter = batch
+                .jitter
+                .map_or(0, |jitter| rand::thread_rng().gen_range(0..=jitter));
+
+            tokio::time::sleep(Duration::from_millis(batch.delay + jitter)).await;
+        }
+
+        record(duration(state, &batch.request).await?);
+    }
+
+    Ok(())
+}
+
+/// Build the probe for a request
+fn probe(state: &AppState, target: &MeasureRequest) -> Result<Probe, MeasureError> {
+    state.limits.check_redirect(target.redirect)?;
+
+    let mut probe = Probe::new(&target.target, target.method.into())?
+        .with_policy(state.policy.clone())?
+        .with_protocol(target.protocol)?
+        .with_resolve(&target.resolve)?
+        .with_resolver(&target.resolver)?
+        .with_family(target.address_family)?;
+
+    check_dual_stack(target.dual_stack, target.address_family, target.fan_out)?;
 
-    client.get("http://fleek-test.network/services/0/ipfs/bafkreidfgseevm6bhqd7wsecqvq5b3kr5bqlje7nbbexfhqsl7mwhnzk3q").send().await?;
+    if let Some(ref headers) = target.headers {
+        probe = probe.with_headers(headers)?;
+    }
+
+    if let Some(ref body) = target.body {
+        probe = probe.with_body(body)?;
+    }
+
+    if let Some(ref verify) = target.verify {
+        probe = probe.with_verify(verify)?;
+    }
+
+    probe.with_capture_headers(&target.capture_headers)
+}
+
+/// Build the probe for a duration request, checking what it asks for around the request
+fn duration_probe(
+    state: &AppState,
+    target: &MeasureDurationRequest,
+) -> Result<Probe, MeasureError> {
+    let probe = probe(state, &target.request)?;
+
+    if let Warmup::Target { count } = target.warmup {
+        if count > state.limits.max_warmup {
+            return Err(MeasureError::BadRequest(format!(
+                "A measurement can have at most {} warmup requests",
+                state.limits.max_warmup
+            )));
+        }
+    }
+
+    if target.warm > state.limits.max_warm {
+        return Err(MeasureError::BadRequest(format!(
+            "A measurement can have at most {} warm requests",
+            state.limits.max_warm
+        )));
+    }
 
-    let method: Method = match target.method.to_uppercase().as_str() {
-        "GET" => Method::GET,
-        "POST" => Method::POST,
-        "PUT" => Method::PUT,
-        "DELETE" => Method::DELETE,
-        _ => Method::GET,
+    if (target.warm > 0 || target.resume) && target.request.redirect != RedirectPolicy::None {
+        return Err(MeasureError::BadRequest(
+            "Warm and resumed requests don't follow redirects".to_string(),
+        ));
+    }
+
+    if target.resume && probe.url.scheme() != "https" {
+        return Err(MeasureError::BadRequest(
+            "Resuming a tls session needs an https target".to_string(),
+        ));
+    }
+
+    Ok(probe)
+}
+
+/// Measure a duration request, including its warmup
+async fn duration(
+    state: &AppState,
+    target: &MeasureDurationRequest,
+) -> Result<MeasureResponse, MeasureError> {
+    let probe = duration_probe(state, target)?;
+
+    let measure = |probe: Probe| async move { measure_probe(state, target, &probe).await };
+
+    let measurement = if target.request.fan_out {
+        each_address(&probe, state.limits.max_addresses, measure).await?
+    } else if target.request.dual_stack {
+        dual_stack(&probe, measure).await?
+    } else {
+        measure_probe(state, target, &probe).await?
     };
 
-    let mut request_builder = client.request(method, &target.target);
+    println!("duration: {:?}", measurement.overall_duration);
+
+    if target.fail_on_error_status {
+        let status = measurement
+            .status
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or_default();
 
-    if let Some(headers) = target.headers {
-        for (key, value) in headers {
-            request_builder = request_builder.header(key, value);
+        if !status.is_success() {
+            return Err(MeasureError::HttpError {
+                status,
+                measurement: Box::new(measurement),
+            });
         }
     }
 
-    if let Some(body) = target.body {
-        let json_body: Value = serde_json::from_str(&body)
-            .map_err(|e| MeasureError::BadRequest(format!("Invalid JSON body: {}", e)))?;
-        request_builder = request_builder.body(json_body.to_string());
+    Ok(measurement)
+}
+
+/// Measure the probe of a duration request after its warmup
+async fn measure_probe(
+    state: &AppState,
+    target: &MeasureDurationRequest,
+    probe: &Probe,
+) -> Result<MeasureResponse, MeasureError> {
+    let warmup = warmup(state, &target.warmup, probe, target.request.redirect).await?;
+    let mut measurement = if target.warm > 0 || target.resume {
+        run_reusing(state, probe, target.warm, target.resume).await?
+    } else {
+        run(state, probe, target.request.redirect).await?
+    };
+
+    if target.include_warmup {
+        measurement.warmup = Some(warmup);
+    }
+
+    Ok(measurement)
+}
+
+/// Measure the probe at up to `max` of the addresses its target resolves to, one after the
+/// other, an address that fails doesn't stop the others from being measured
+///
+/// The measurement is of the first address that could be measured, with the results of the
+/// others in its `addresses`, it's the first address's error if none could be
+async fn each_address<F, Fut>(
+    probe: &Probe,
+    max: usize,
+    mut measure: F,
+) -> Result<MeasureResponse, MeasureError>
+where
+    F: FnMut(Probe) -> Fut,
+    Fut: Future<Output = Result<MeasureResponse, MeasureError>>,
+{
+    let mut ips = engine::lookup(probe).await?;
+    ips.truncate(max.max(1));
+
+    let mut results = Vec::with_capacity(ips.len());
+    for ip in ips {
+        results.push((ip, measure(probe.pinned(ip)).await));
+    }
+
+    let Some(first) = results.iter().position(|(_, result)| result.is_ok()) else {
+        let (_, result) = results.swap_remove(0);
+        return result;
+    };
+
+    let (_, result) = results.remove(first);
+    let mut measurement = result?;
+
+    let addresses = results
+        .into_iter()
+        .map(|(ip, result)| {
+            let (measurement, error) = match result {
+                Ok(measurement) => (Some(measurement), None),
+                Err(e) => (None, Some(e.into())),
+            };
+
+            AddressMeasurement {
+                ip: ip.to_string(),
+                measurement,
+                error,
+            }
+        })
+        .collect();
+
+    measurement.addresses = Some(addresses);
+
+    Ok(measurement)
+}
+
+/// Measure the probe over ipv4 and then over ipv6
+///
+/// The measurement is the ipv4 one, with the ipv6 one in its `ipv6`, or in its `ipv6_error`
+/// why there is none, a target or host without ipv6 still gets its ipv4 measurement
+async fn dual_stack<F, Fut>(probe: &Probe, mut measure: F) -> Result<MeasureResponse, MeasureError>
+where
+    F: FnMut(Probe) -> Fut,
+    Fut: Future<Output = Result<MeasureResponse, MeasureError>>,
+{
+    let mut measurement = measure(probe.clone().with_family(AddressFamily::V4)?).await?;
+    match measure(probe.clone().with_family(AddressFamily::V6)?).await {
+        Ok(ipv6) => measurement.ipv6 = Some(Box::new(ipv6)),
+        Err(e) => measurement.ipv6_error = Some(Box::new(e.into())),
+    }
+
+    Ok(measurement)
+}
+
+/// Dual stack picks the family of each of its measurements, so it can't be combined with
+/// a family of the request's own or with measuring every address
+fn check_dual_stack(
+    dual_stack: bool,
+    family: AddressFamily,
+    fan_out: bool,
+) -> Result<(), MeasureError> {
+    if dual_stack && family != AddressFamily::Any {
+        return Err(MeasureError::BadRequest(
+            "Dual stack measures both address families, it can't be given one".to_string(),
+        ));
+    }
+
+    if dual_stack && fan_out {
+        return Err(MeasureError::BadRequest(
+            "Dual stack can't be combined with fanning out to every address".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Run the warmup requests of the policy, following redirects like the measured request,
+/// returning their measurements
+async fn warmup(
+    state: &AppState,
+    policy: &Warmup,
+    probe: &Probe,
+    redirect: RedirectPolicy,
+) -> Result<Vec<MeasureResponse>, MeasureError> {
+    match policy {
+        Warmup::None => Ok(Vec::new()),
+        Warmup::Target { count } => {
+            let mut measurements = Vec::new();
+            for _ in 0..*count {
+                measurements.push(run(state, probe, redirect).await?);
+            }
+
+            Ok(measurements)
+        }
+        Warmup::Url { url } => {
+            let mut warmup = Probe::new(url, Method::GET)?.with_resolve(&probe.resolve)?;
+            warmup.resolver = probe.resolver.clone();
+            warmup.family = probe.family;
+
+            if let Some(ref target_policy) = probe.policy {
+                warmup = warmup.with_policy(target_policy.clone())?;
+            }
+
+            Ok(vec![run(state, &warmup, redirect).await?])
+        }
     }
+}
+
+/// Run a probe, following the redirects the policy allows, every request of the chain
+/// is recorded in the metrics
+///
+/// The measurement is of the response the chain ended at, a redirect if there were more
+/// than the policy allows, with the redirects before it
+async fn run(
+    state: &AppState,
+    probe: &Probe,
+    redirect: RedirectPolicy,
+) -> Result<MeasureResponse, MeasureError> {
+    let _in_flight = state.metrics.probe();
+    let max = match redirect {
+        RedirectPolicy::None => 0,
+        RedirectPolicy::Follow { max } => max,
+    };
 
     let start = Instant::now();
-    let response = request_builder.send().await;
+    let mut probe = Cow::Borrowed(probe);
+    let mut redirects = Vec::new();
+
+    loop {
+        let mut measurement = engine::measure(&probe).await?;
+
+        let next = if redirects.len() < max {
+            probe.redirected(&measurement)?
+        } else {
+            None
+        };
 
-    match response {
-        Ok(response) => {
-            if !response.status().is_success() {
-                return Err(MeasureError::HttpError(response.status()));
+        let Some(next) = next else {
+            state.metrics.observe(&probe, &measurement);
+
+            if !redirects.is_empty() {
+                measurement.url = Some(probe.url.to_string());
+                measurement.redirects = Some(redirects);
+                measurement.chain_duration = Some(start.elapsed());
             }
 
-            let text = response.text().await.unwrap();
-            let duration = start.elapsed();
-        
-            println!("response: {:?}", text);
-            println!("duration: {:?}", duration);
-
-            Ok(Json(MeasureResponse {
-                ip: "".to_string(),
-                dns_lookup_duration: None,
-                tcp_connect_duration: Duration::from_secs(0),
-                http_get_send_duration: Duration::from_secs(0),
-                ttfb_duration: Duration::from_secs(0),
-                tls_handshake_duration: None,
-                overall_duration: Some(duration),
-            }))
+            return Ok(measurement);
+        };
+
+        // the body of a redirect isn't the one that was asked for
+        measurement.verification = None;
+        measurement.url = Some(probe.url.to_string());
+        state.metrics.observe(&probe, &measurement);
+
+        redirects.push(measurement);
+        probe = Cow::Owned(next);
+    }
+}
+
+/// Run a probe, then `warm` more times over its connection and once over a new connection
+/// resuming its tls session if asked to, their measurements are nested in the first one
+async fn run_reusing(
+    state: &AppState,
+    probe: &Probe,
+    warm: usize,
+    resume: bool,
+) -> Result<MeasureResponse, MeasureError> {
+    let _in_flight = state.metrics.probe();
+    let (mut connection, mut measurement, _) = engine::open(probe).await?;
+
+    state.metrics.observe(probe, &measurement);
+
+    if warm > 0 {
+        let mut measurements = Vec::new();
+        for _ in 0..warm {
+            match connection.fetch(probe).await {
+                Ok((warm, _)) => {
+                    state.metrics.observe(probe, &warm);
+                    measurements.push(warm);
+                }
+                // the target can close the connection, the cold measurement still stands
+                Err(e) => {
+                    measurement.warm_error = Some(Box::new(e.into()));
+                    break;
+                }
+            }
         }
-        Err(e) => Err(MeasureError::from(e)),
+
+        measurement.warm = Some(measurements);
     }
+
+    if resume {
+        let (_, resumed, _) = connection.resume(probe).await?;
+        state.metrics.observe(probe, &resumed);
+        measurement.resumed = Some(Box::new(resumed));
+    }
+
+    Ok(measurement)
 }
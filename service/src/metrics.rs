@@ -0,0 +1,278 @@
+//! Prometheus metrics of the probes the service ran
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use measure::{
+    engine::Probe, policy::TargetPolicy, ConnectionKind, MeasureError, MeasureResponse, Phase,
+};
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Buckets from 1ms to ~30s, probes of far away targets routinely take seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// The target label of probes to hosts past the cap
+const OTHER_TARGET: &str = "other";
+
+pub struct Metrics {
+    registry: Registry,
+    /// Hosts in its allow list are always labelled
+    policy: Arc<TargetPolicy>,
+    /// The hosts of the scheduled targets, always labelled
+    scheduled: HashSet<String>,
+    /// The other hosts labelled so far, in the order they were first probed
+    targets: Mutex<HashSet<String>>,
+    /// The most other hosts labelled, probes to any more share the `other` label
+    max_targets: usize,
+    /// Phase durations in seconds, by target host and phase
+    phase_duration: HistogramVec,
+    /// Completed probes by target host and response status
+    probes: IntCounterVec,
+    /// Checked response bodies by target host and whether they matched
+    verifications: IntCounterVec,
+    /// Failed requests to the service by error variant and the phase it happened in
+    errors: IntCounterVec,
+    probes_in_flight: IntGauge,
+    /// Requests being handled by route
+    requests_in_flight: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new(
+        policy: Arc<TargetPolicy>,
+        scheduled: HashSet<String>,
+        max_targets: usize,
+    ) -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("measure".to_string()), None)?;
+
+        let phase_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "phase_duration_seconds",
+                "How long each phase of a probe took",
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+            &["target", "phase", "connection"],
+        )?;
+        let probes = IntCounterVec::new(
+            Opts::new("probes_total", "Probes that got a response from the target"),
+            &["target", "status"],
+        )?;
+        let verifications = IntCounterVec::new(
+            Opts::new(
+                "verifications_total",
+                "Response bodies checked against their expected digest",
+            ),
+            &["target", "result"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Requests the service failed, by error"),
+            &["error", "phase"],
+        )?;
+        let probes_in_flight = IntGauge::new("probes_in_flight", "Probes currently running")?;
+        let requests_in_flight = IntGaugeVec::new(
+            Opts::new("requests_in_flight", "Requests currently being handled"),
+            &["route"],
+        )?;
+
+        registry.register(Box::new(phase_duration.clone()))?;
+        registry.register(Box::new(probes.clone()))?;
+        registry.register(Box::new(verifications.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(probes_in_flight.clone()))?;
+        registry.register(Box::new(requests_in_flight.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            policy,
+            scheduled,
+            targets: Mutex::new(HashSet::new()),
+            max_targets,
+            phase_duration,
+            probes,
+            verifications,
+            errors,
+            probes_in_flight,
+            requests_in_flight,
+        })
+    }
+
+    /// Count a request to a route as in flight until the guard is dropped
+    pub fn request(&self, route: &str) -> InFlight {
+        InFlight::new(self.requests_in_flight.with_label_values(&[route]))
+    }
+
+    /// Count a probe as in flight until the guard is dropped
+    pub fn probe(&self) -> InFlight {
+        InFlight::new(self.probes_in_flight.clone())
+    }
+
+    /// Record the timings of a probe that got a response
+    pub fn observe(&self, probe: &Probe, measurement: &MeasureResponse) {
+        let target = self.target(probe);
+        let status = measurement
+            .status
+            .map_or_else(|| "unknown".to_string(), |status| status.to_string());
+
+        self.probes.with_label_values(&[&target, &status]).inc();
+
+        if let Some(ref verification) = measurement.verification {
+            let result = if verification.verified {
+                "passed"
+            } else {
+                "failed"
+            };
+
+            self.verifications
+                .with_label_values(&[&target, result])
+                .inc();
+        }
+
+        let phases = [
+            (Phase::Dns.name(), measurement.dns_lookup_duration),
+            // http/3 has no tcp connection, and a warm request didn't make one
+            (
+                Phase::TcpConnect.name(),
+                Some(measurement.tcp_connect_duration).filter(|_| {
+                    measurement.quic_handshake_duration.is_none()
+                        && measurement.connection != ConnectionKind::Warm
+                }),
+            ),
+            (
+                Phase::TlsHandshake.name(),
+                measurement.tls_handshake_duration,
+            ),
+            (
+                Phase::QuicHandshake.name(),
+                measurement.quic_handshake_duration,
+            ),
+            (Phase::Send.name(), Some(measurement.http_get_send_duration)),
+            (Phase::FirstByte.name(), Some(measurement.ttfb_duration)),
+            ("last_byte", measurement.ttlb_duration),
+            ("overall", measurement.overall_duration),
+        ];
+
+        for (phase, duration) in phases {
+            if let Some(duration) = duration {
+                self.observe_phase(&target, phase, measurement.connection, duration);
+            }
+        }
+    }
+
+    /// The host of the probe's target, allowed and scheduled hosts are always labelled and
+    /// the first `max_targets` others, later hosts share one label so requests can't add
+    /// series without bound
+    fn target(&self, probe: &Probe) -> String {
+        let Some(host) = probe.url.host_str() else {
+            return OTHER_TARGET.to_string();
+        };
+
+        if self.policy.lists(host) || self.scheduled.contains(host) {
+            return host.to_string();
+        }
+
+        let mut targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        if targets.contains(host) || targets.len() < self.max_targets {
+            targets.insert(host.to_string());
+            return host.to_string();
+        }
+
+        OTHER_TARGET.to_string()
+    }
+
+    fn observe_phase(
+        &self,
+        target: &str,
+        phase: &str,
+        connection: ConnectionKind,
+        duration: Duration,
+    ) {
+        self.phase_duration
+            .with_label_values(&[target, phase, connection.name()])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Count the error if the request failed, passing the result through
+    pub fn result<T>(&self, result: Result<T, MeasureError>) -> Result<T, MeasureError> {
+        if let Err(ref e) = result {
+            let phase = match e {
+                MeasureError::Phase { phase, .. } => phase.name(),
+                _ => "none",
+            };
+
+            self.errors.with_label_values(&[e.name(), phase]).inc();
+        }
+
+        result
+    }
+
+    /// The metrics in the prometheus text format
+    pub fn encode(&self) -> prometheus::Result<String> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
+
+/// Decrements its gauge when dropped, so cancelled requests aren't counted forever
+pub struct InFlight(IntGauge);
+
+impl InFlight {
+    fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+
+        InFlight(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use super::*;
+
+    fn target(metrics: &Metrics, url: &str) -> String {
+        metrics.target(&Probe::new(url, Method::GET).unwrap())
+    }
+
+    #[test]
+    fn targets_are_labelled_by_host_up_to_the_cap() {
+        let policy = TargetPolicy {
+            allow_hosts: vec!["*.allowed.test".to_string()],
+            ..Default::default()
+        };
+        let scheduled = HashSet::from(["scheduled.test".to_string()]);
+        let metrics = Metrics::new(Arc::new(policy), scheduled, 2).unwrap();
+
+        assert_eq!(target(&metrics, "https://a.test/x"), "a.test");
+        assert_eq!(target(&metrics, "http://b.test:8080/"), "b.test");
+        assert_eq!(target(&metrics, "https://c.test/"), OTHER_TARGET);
+        // hosts labelled before the cap keep their label
+        assert_eq!(target(&metrics, "https://a.test/y"), "a.test");
+        assert_eq!(
+            target(&metrics, "https://www.allowed.test/"),
+            "www.allowed.test"
+        );
+        assert_eq!(
+            target(&metrics, "https://scheduled.test/"),
+            "scheduled.test"
+        );
+    }
+
+    #[test]
+    fn no_targets_are_labelled_without_a_cap() {
+        let metrics = Metrics::new(Arc::new(TargetPolicy::default()), HashSet::new(), 0).unwrap();
+
+        assert_eq!(target(&metrics, "https://a.test/"), OTHER_TARGET);
+    }
+}
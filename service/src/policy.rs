@@ -0,0 +1,321 @@
+//! The policy deciding which targets the service will send requests to
+//!
+//! Urls are checked for their scheme, host and port before anything is sent, and the address
+//! a host resolves to is checked against the denied networks before connecting, so a public
+//! name pointing at a private address is still refused
+//!
+//! Ipv6 addresses that reach an ipv4 host, mapped, nat64 and 6to4 ones, are also checked
+//! for the ipv4 address they embed
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use url::{Host, Url};
+
+use crate::MeasureError;
+
+/// The networks refused by default, loopback, private, link-local (cloud metadata) and
+/// other non public ranges
+const DEFAULT_DENY_CIDRS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    // nat64 for local use, its prefix length and so where it embeds the address can vary
+    "64:ff9b:1::/48",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetPolicy {
+    /// The url schemes targets may use
+    pub schemes: Vec<String>,
+    /// Host globs a target must match one of, `*` matches any run of characters,
+    /// any host is allowed if empty
+    pub allow_hosts: Vec<String>,
+    /// Host globs a target must not match
+    pub deny_hosts: Vec<String>,
+    /// Networks the target's address must not be in, checked after dns resolution
+    pub deny_cidrs: Vec<IpNet>,
+    /// The ports targets may use, any port is allowed if empty
+    pub ports: Vec<u16>,
+}
+
+impl Default for TargetPolicy {
+    fn default() -> Self {
+        TargetPolicy {
+            schemes: vec!["http".to_string(), "https".to_string()],
+            allow_hosts: Vec::new(),
+            deny_hosts: Vec::new(),
+            deny_cidrs: DEFAULT_DENY_CIDRS
+                .iter()
+                .map(|cidr| cidr.parse().expect("valid default cidr"))
+                .collect(),
+            ports: Vec::new(),
+        }
+    }
+}
+
+impl TargetPolicy {
+    /// Check the scheme, host and port of a target url
+    pub fn check_url(&self, url: &Url) -> Result<(), MeasureError> {
+        if !self
+            .schemes
+            .iter()
+            .any(|scheme| scheme.eq_ignore_ascii_case(url.scheme()))
+        {
+            return Err(MeasureError::TargetDenied(format!(
+                "scheme {} is not allowed",
+                url.scheme()
+            )));
+        }
+
+        let host = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => {
+                return Err(MeasureError::BadRequest(
+                    "Target url has no host".to_string(),
+                ))
+            }
+        };
+
+        self.check_host(&host)?;
+
+        if let Some(port) = url.port_or_known_default() {
+            if !self.ports.is_empty() && !self.ports.contains(&port) {
+                return Err(MeasureError::TargetDenied(format!(
+                    "port {} is not allowed",
+                    port
+                )));
+            }
+        }
+
+        // ip literals don't go through dns, refuse them before the request is built
+        match url.host() {
+            Some(Host::Ipv4(ip)) => self.check_ip(ip.into()),
+            Some(Host::Ipv6(ip)) => self.check_ip(ip.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a host against the allowed and denied hosts
+    pub fn check_host(&self, host: &str) -> Result<(), MeasureError> {
+        let host = host.trim_end_matches('.');
+
+        if !self.allow_hosts.is_empty() && !self.allow_hosts.iter().any(|glob| matches(glob, host))
+        {
+            return Err(MeasureError::TargetDenied(format!(
+                "host {} is not allowed",
+                host
+            )));
+        }
+
+        if self.deny_hosts.iter().any(|glob| matches(glob, host)) {
+            return Err(MeasureError::TargetDenied(format!(
+                "host {} is denied",
+                host
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the host is one of the allowed hosts, false when any host is allowed
+    pub fn lists(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+
+        self.allow_hosts.iter().any(|glob| matches(glob, host))
+    }
+
+    /// Check the address a target resolved to, and the ipv4 address it reaches if it
+    /// embeds one
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), MeasureError> {
+        let embedded = match ip {
+            IpAddr::V6(ip) => embedded_ipv4(ip),
+            IpAddr::V4(_) => None,
+        };
+
+        for checked in std::iter::once(ip).chain(embedded.map(IpAddr::V4)) {
+            if let Some(net) = self.deny_cidrs.iter().find(|net| net.contains(&checked)) {
+                let reason = if checked == ip {
+                    format!("address {} is in the denied network {}", ip, net)
+                } else {
+                    format!(
+                        "address {} reaches {} in the denied network {}",
+                        ip, checked, net
+                    )
+                };
+
+                return Err(MeasureError::TargetDenied(reason));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The ipv4 address an ipv4 mapped, well-known prefix nat64 or 6to4 address reaches
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    let ipv4 =
+        |at: usize| Ipv4Addr::new(octets[at], octets[at + 1], octets[at + 2], octets[at + 3]);
+
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] | [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(ipv4(12)),
+        [0x2002, ..] => Some(ipv4(2)),
+        _ => None,
+    }
+}
+
+/// Case insensitive glob match, `*` matches any run of characters
+fn matches(glob: &str, host: &str) -> bool {
+    let glob = glob.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+
+    let mut parts = glob.split('*');
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut rest) = host.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // no wildcard, the glob must match the whole host
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn denied(policy: &TargetPolicy, ip: &str) -> bool {
+        policy.check_ip(ip.parse().unwrap()).is_err()
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(matches("example.com", "example.com"));
+        assert!(matches("Example.COM", "example.com"));
+        assert!(!matches("example.com", "www.example.com"));
+        assert!(!matches("example.com", "example.com.evil"));
+        assert!(matches("*.example.com", "www.example.com"));
+        assert!(matches("*.example.com", "a.b.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", "example.com.evil"));
+        assert!(matches("api.*.example.com", "api.eu.example.com"));
+        assert!(!matches("api.*.example.com", "www.eu.example.com"));
+        assert!(matches("*", "anything"));
+        assert!(matches("a*a", "aa"));
+        assert!(!matches("a*a", "a"));
+    }
+
+    #[test]
+    fn lists_only_allowed_hosts() {
+        assert!(!TargetPolicy::default().lists("example.com"));
+
+        let policy = TargetPolicy {
+            allow_hosts: vec!["*.example.com".to_string()],
+            ..Default::default()
+        };
+
+        assert!(policy.lists("www.example.com"));
+        assert!(policy.lists("www.example.com."));
+        assert!(!policy.lists("example.org"));
+    }
+
+    #[test]
+    fn check_ip_denies_non_public_addresses() {
+        let policy = TargetPolicy::default();
+
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.5",
+            "169.254.169.254",
+            "192.168.1.1",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+        ] {
+            assert!(denied(&policy, ip), "{} should be denied", ip);
+        }
+
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(!denied(&policy, ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn check_ip_denies_embedded_ipv4() {
+        let policy = TargetPolicy::default();
+
+        // mapped, nat64 and 6to4 addresses reaching private and link-local ipv4 hosts
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:5",
+            "64:ff9b::169.254.169.254",
+            "2002:a00:5::1",
+            "2002:a9fe:a9fe::",
+            "64:ff9b:1::5db8:d822",
+        ] {
+            assert!(denied(&policy, ip), "{} should be denied", ip);
+        }
+
+        // the same kinds of addresses reaching a public host
+        for ip in [
+            "::ffff:93.184.216.34",
+            "64:ff9b::5db8:d822",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(!denied(&policy, ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn check_ip_with_an_empty_deny_list_allows_anything() {
+        let policy = TargetPolicy {
+            deny_cidrs: Vec::new(),
+            ..Default::default()
+        };
+
+        assert!(!denied(&policy, "127.0.0.1"));
+        assert!(!denied(&policy, "64:ff9b::a00:5"));
+    }
+
+    #[test]
+    fn check_url_denies_ip_literals() {
+        let policy = TargetPolicy::default();
+        let check = |url: &str| policy.check_url(&Url::parse(url).unwrap());
+
+        assert!(check("https://example.com/").is_ok());
+        assert!(check("https://10.0.0.5/").is_err());
+        assert!(check("https://[64:ff9b::a00:5]/").is_err());
+        assert!(check("ftp://example.com/").is_err());
+    }
+}
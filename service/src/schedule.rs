@@ -0,0 +1,77 @@
+//! Measurements the service takes on its own, on an interval or a cron schedule,
+//! recorded in the history
+
+use std::time::{Duration, SystemTime};
+
+use chrono::Utc;
+use measure::{HistoryEntry, MeasureDurationRequest};
+use tokio::time::MissedTickBehavior;
+
+use crate::AppState;
+
+/// When a schedule runs
+#[derive(Debug, Clone)]
+pub enum Cadence {
+    Every(Duration),
+    /// A cron expression with a seconds field, in utc
+    Cron(Box<cron::Schedule>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub name: String,
+    pub cadence: Cadence,
+    pub request: MeasureDurationRequest,
+}
+
+impl Schedule {
+    /// Run the schedule for as long as the service runs
+    pub fn spawn(self, state: AppState) {
+        println!(
+            "Scheduled {} measuring {:?}",
+            self.name, self.request.request.target
+        );
+
+        tokio::spawn(async move {
+            match self.cadence {
+                Cadence::Every(period) => {
+                    let mut interval = tokio::time::interval(period);
+                    // a slow measurement delays the next rather than bunching them up
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                    loop {
+                        interval.tick().await;
+                        self.run(&state).await;
+                    }
+                }
+                Cadence::Cron(ref cron) => {
+                    while let Some(next) = cron.upcoming(Utc).next() {
+                        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                        tokio::time::sleep(wait).await;
+
+                        self.run(&state).await;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn run(&self, state: &AppState) {
+        let time = SystemTime::now();
+        let result = state
+            .metrics
+            .result(crate::duration(state, &self.request).await);
+
+        let entry = HistoryEntry {
+            schedule: self.name.clone(),
+            target: self.request.request.target.clone(),
+            time,
+            measurement: result.as_ref().ok().cloned(),
+            error: result.err().map(Into::into),
+        };
+
+        if let Err(e) = state.history.append(&entry).await {
+            println!("Failed to record the measurement of {}: {}", self.name, e);
+        }
+    }
+}
@@ -1,67 +1,831 @@
 use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
-use ttfb::{TtfbError, TtfbOutcome};
 
+/// A request to measure, the fields every measurement shares
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MeasureRequest {
     pub target: String,
+    /// The method of the request to the target, defaults to get
+    #[serde(default)]
+    pub method: HttpMethod,
+    pub headers: Option<HashMap<String, String>>,
+    pub body: Option<RequestBody>,
+    /// The http version to measure with, defaults to http/1.1
+    #[serde(default)]
+    pub protocol: HttpProtocol,
+    /// Whether redirects from the target are followed, defaults to measuring the first response
+    #[serde(default)]
+    pub redirect: RedirectPolicy,
+    /// The addresses to connect to for these hosts instead of resolving them, the host header
+    /// and sni are still those of the url
+    #[serde(default)]
+    pub resolve: HashMap<String, IpAddr>,
+    /// Measure every address the target's host resolves to, each on its own
+    #[serde(default)]
+    pub fan_out: bool,
+    /// The resolver the hosts are looked up with, defaults to the system's
+    #[serde(default)]
+    pub resolver: DnsResolver,
+    /// The address family the target is connected to over, defaults to the first address
+    #[serde(default)]
+    pub address_family: AddressFamily,
+    /// Measure over ipv4 and over ipv6, the ipv6 measurement is paired with the ipv4 one
+    #[serde(default)]
+    pub dual_stack: bool,
+    /// The response headers to record in the measurement
+    #[serde(default)]
+    pub capture_headers: Vec<String>,
+    /// Check the response body against a digest, the result is recorded in the measurement
+    #[serde(default)]
+    pub verify: Option<Verify>,
 }
 
+/// A request to measure with its warmup and the requests after it
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MeasureDurationRequest {
-    pub target: String,
-    pub method: String,
-    pub headers: Option<HashMap<String, String>>,
-    pub body: Option<String>,
+    #[serde(flatten)]
+    pub request: MeasureRequest,
+    /// The requests to make before the measured one, defaults to none
+    #[serde(default)]
+    pub warmup: Warmup,
+    /// Return the warmup measurements alongside the measured request
+    #[serde(default)]
+    pub include_warmup: bool,
+    /// The requests to make over the measured request's connection once it's done,
+    /// each measured on its own
+    #[serde(default)]
+    pub warm: usize,
+    /// Also measure the request over a new connection resuming the first one's tls session
+    #[serde(default)]
+    pub resume: bool,
+    /// Respond with an error instead of a measurement if the target status isn't a success
+    #[serde(default)]
+    pub fail_on_error_status: bool,
+}
+
+/// The http methods the service can measure, parsed case insensitively
+///
+/// `CONNECT` is left out, it asks a proxy for a tunnel rather than a resource
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    #[default]
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+    Trace,
+}
+
+impl FromStr for HttpMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "GET" => Ok(HttpMethod::Get),
+            "HEAD" => Ok(HttpMethod::Head),
+            "POST" => Ok(HttpMethod::Post),
+            "PUT" => Ok(HttpMethod::Put),
+            "PATCH" => Ok(HttpMethod::Patch),
+            "DELETE" => Ok(HttpMethod::Delete),
+            "OPTIONS" => Ok(HttpMethod::Options),
+            "TRACE" => Ok(HttpMethod::Trace),
+            _ => Err(format!("unsupported http method: {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for HttpMethod {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<HttpMethod> for http::Method {
+    fn from(method: HttpMethod) -> Self {
+        match method {
+            HttpMethod::Get => http::Method::GET,
+            HttpMethod::Head => http::Method::HEAD,
+            HttpMethod::Post => http::Method::POST,
+            HttpMethod::Put => http::Method::PUT,
+            HttpMethod::Patch => http::Method::PATCH,
+            HttpMethod::Delete => http::Method::DELETE,
+            HttpMethod::Options => http::Method::OPTIONS,
+            HttpMethod::Trace => http::Method::TRACE,
+        }
+    }
 }
 
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(http::Method::from(*self).as_str())
+    }
+}
+
+/// The http version a request to the target is made with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpProtocol {
+    /// Http/1.1, over tls or plain tcp
+    #[default]
+    H1,
+    /// Http/2, negotiated with alpn, the target may still answer with http/1.1
+    H2,
+    /// Http/3 over quic
+    H3,
+}
+
+impl FromStr for HttpProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "h1" | "http/1.1" => Ok(HttpProtocol::H1),
+            "h2" => Ok(HttpProtocol::H2),
+            "h3" => Ok(HttpProtocol::H3),
+            _ => Err(format!("unsupported http protocol: {}", s)),
+        }
+    }
+}
+
+/// The addresses of the target a request connects to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    /// The first address the host resolved to, of either family
+    #[default]
+    Any,
+    /// Only ipv4 addresses
+    V4,
+    /// Only ipv6 addresses
+    V6,
+    /// Addresses of both families raced with happy eyeballs, ipv6 first, the first to
+    /// connect is measured
+    Both,
+}
+
+impl FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "any" => Ok(AddressFamily::Any),
+            "v4" | "ipv4" => Ok(AddressFamily::V4),
+            "v6" | "ipv6" => Ok(AddressFamily::V6),
+            "both" => Ok(AddressFamily::Both),
+            _ => Err(format!("unsupported address family: {}", s)),
+        }
+    }
+}
+
+/// The body of the request to the target, tagged with how it's encoded
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "encoding", content = "data", rename_all = "snake_case")]
+pub enum RequestBody {
+    /// Any json value
+    Json(serde_json::Value),
+    /// Sent as is
+    Text(String),
+    /// Base64 encoded bytes, sent decoded
+    Base64(String),
+    /// Form fields, sent url encoded
+    Form(Vec<(String, String)>),
+}
+
+impl RequestBody {
+    /// The bytes to send to the target and the content type they are sent with
+    /// if the request doesn't set its own
+    pub fn encode(&self) -> Result<(&'static str, Bytes), MeasureError> {
+        match self {
+            RequestBody::Json(value) => Ok(("application/json", Bytes::from(value.to_string()))),
+            RequestBody::Text(text) => Ok(("text/plain; charset=utf-8", Bytes::from(text.clone()))),
+            RequestBody::Base64(data) => {
+                let bytes = STANDARD
+                    .decode(data)
+                    .map_err(|e| MeasureError::BadRequest(format!("Invalid base64 body: {}", e)))?;
+
+                Ok(("application/octet-stream", Bytes::from(bytes)))
+            }
+            RequestBody::Form(fields) => {
+                let encoded = url::form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(fields)
+                    .finish();
+
+                Ok(("application/x-www-form-urlencoded", Bytes::from(encoded)))
+            }
+        }
+    }
+}
+
+/// Repeats a duration request on the service, so the sampling cadence isn't affected
+/// by the latency between the client and the service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeasureBatchRequest {
+    /// The request to measure for every sample
+    pub request: MeasureDurationRequest,
+    /// The number of samples to take
+    pub times: usize,
+    /// The delay in milliseconds between each sample
+    pub delay: u64,
+    /// A random extra delay of up to this many milliseconds added to each delay
+    #[serde(default)]
+    pub jitter: Option<u64>,
+}
+
+/// What a response body is checked against
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Verify {
+    /// The hex encoded sha2-256 digest of the body
+    Sha256 { digest: String },
+    /// The sha2-256 multihash of a raw cid, other codecs are decoded by gateways so their
+    /// bodies aren't the block. Taken from the target's `/ipfs/{cid}` path or `{cid}.ipfs`
+    /// subdomain if unset
+    Cid { cid: Option<String> },
+}
+
+/// The measurement of one of the target's addresses when every address is measured, or why
+/// it couldn't be taken
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressMeasurement {
+    pub ip: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<MeasureResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+/// The result of checking a response body against its expected digest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verification {
+    /// Whether the body matched
+    pub verified: bool,
+    /// The expected sha2-256 digest, hex encoded
+    pub expected: String,
+    /// The sha2-256 digest of the body, hex encoded
+    pub digest: String,
+}
+
+/// What the service does before measuring a request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Warmup {
+    /// Measure the request cold
+    #[default]
+    None,
+    /// Send the same request to the target `count` times first
+    Target { count: usize },
+    /// Send a get request to `url` first
+    Url { url: String },
+}
+
+/// Which redirects from the target are followed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RedirectPolicy {
+    /// Measure the first response, redirect or not
+    #[default]
+    None,
+    /// Follow up to `max` redirects, measuring every request of the chain
+    Follow { max: usize },
+}
+
+/// The dns resolver hosts are looked up with, servers are given as `ip` or `ip:port`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DnsResolver {
+    /// The resolver of the host the service runs on, through getaddrinfo
+    #[default]
+    System,
+    /// A dns server over udp, on port 53 unless given
+    Udp { server: String },
+    /// A dns server over tcp, on port 53 unless given
+    Tcp { server: String },
+    /// Dns over tls, on port 853 unless given, the server's certificate is checked for `name`
+    Tls { server: String, name: String },
+    /// Dns over https, on port 443 unless given, the server's certificate is checked for
+    /// `name` and queries are posted to `path`, `/dns-query` by default
+    Https {
+        server: String,
+        name: String,
+        #[serde(default)]
+        path: Option<String>,
+    },
+}
+
+/// A dns lookup of a name, without a request to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsRequest {
+    /// The name to look up
+    pub name: String,
+    /// The record types to query, one after the other, defaults to `A` and `AAAA`
+    #[serde(default = "default_record_types")]
+    pub record_types: Vec<String>,
+    #[serde(default)]
+    pub resolver: DnsResolver,
+}
+
+fn default_record_types() -> Vec<String> {
+    vec!["A".to_string(), "AAAA".to_string()]
+}
+
+/// The answers to the queries of a dns lookup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsResponse {
+    /// When the first query was sent
+    pub timestamp: SystemTime,
+    pub name: String,
+    pub queries: Vec<DnsQuery>,
+}
+
+/// A query for one record type and its answers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsQuery {
+    pub record_type: String,
+    /// From the query being sent to the answer, over a new connection to the server
+    pub duration: Duration,
+    /// The response code of the server, like `NoError` or `NXDomain`, the system resolver
+    /// doesn't expose it
+    pub response_code: Option<String>,
+    /// The records answered, cnames included, empty if there were none
+    pub answers: Vec<DnsAnswer>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsAnswer {
+    pub name: String,
+    pub record_type: String,
+    /// In seconds, the system resolver doesn't expose it
+    pub ttl: Option<u32>,
+    /// The record's data in its presentation format, like an address or a cname's target
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct MeasureResponse {
+    /// When the measured request was started
+    #[serde(default)]
+    pub timestamp: Option<SystemTime>,
+    /// The url the request was made to, set on the requests of a redirect chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// How the connection the request was made over came about
+    #[serde(default)]
+    pub connection: ConnectionKind,
     pub ip: String,
     pub dns_lookup_duration: Option<Duration>,
     pub tcp_connect_duration: Duration,
     pub http_get_send_duration: Duration,
     pub ttfb_duration: Duration,
+    /// From the request being sent to the last byte of the response
+    #[serde(default)]
+    pub ttlb_duration: Option<Duration>,
     pub tls_handshake_duration: Option<Duration>,
+    /// The quic handshake, tls included, of an http/3 request
+    #[serde(default)]
+    pub quic_handshake_duration: Option<Duration>,
     pub overall_duration: Option<Duration>,
+    /// The status code the target responded with
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// Where the target redirected to, for a redirect response
+    #[serde(default)]
+    pub location: Option<String>,
+    /// The size of the response body in bytes, as it was sent with its content encoding
+    #[serde(default)]
+    pub response_size: Option<u64>,
+    /// The size of the body with its content encoding undone, unset if the encoding
+    /// isn't supported
+    #[serde(default)]
+    pub decoded_size: Option<u64>,
+    /// Every byte received from the target, the tls or quic handshake and http framing included
+    #[serde(default)]
+    pub bytes_received: Option<u64>,
+    /// The rate the body arrived at from its first byte to its last, in bytes per second
+    #[serde(default)]
+    pub average_throughput: Option<f64>,
+    /// The fastest rate the body arrived at over a 100ms window, in bytes per second
+    #[serde(default)]
+    pub peak_throughput: Option<f64>,
+    /// The http version the target answered with, `http/1.1`, `h2` or `h3`
+    #[serde(default)]
+    pub protocol: Option<String>,
+    /// What was negotiated in the tls handshake, for https targets
+    #[serde(default)]
+    pub tls: Option<TlsInfo>,
+    /// The result of checking the body, if it was asked for
+    #[serde(default)]
+    pub verification: Option<Verification>,
+    /// The captured response headers, multiple values are joined with a comma
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// The results of the other addresses the target's host resolved to, when every
+    /// address was measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addresses: Option<Vec<AddressMeasurement>>,
+    /// The measurement over ipv6 paired with this one over ipv4, in dual stack mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Box<MeasureResponse>>,
+    /// Why the target couldn't be measured over ipv6 in dual stack mode, this one over ipv4
+    /// is kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_error: Option<Box<ErrorResponse>>,
+    /// The measurements of the warmup requests, if they were asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warmup: Option<Vec<MeasureResponse>>,
+    /// The measurements of the redirects followed to get to this response, in order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirects: Option<Vec<MeasureResponse>>,
+    /// From the first request of a redirect chain to the end of this one
+    #[serde(default)]
+    pub chain_duration: Option<Duration>,
+    /// The measurements of the requests made over the connection after this one, if they
+    /// were asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warm: Option<Vec<MeasureResponse>>,
+    /// Why the warm requests stopped short, the ones before it are in `warm`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warm_error: Option<Box<ErrorResponse>>,
+    /// The measurement of the request over a connection resuming this one's tls session,
+    /// if it was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumed: Option<Box<MeasureResponse>>,
+}
+
+/// How the connection of a measurement came about, the setup phases of a warm request are
+/// zero and a resumed one's tls handshake is abbreviated if the target agreed to it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionKind {
+    /// A new connection with a full handshake
+    #[default]
+    Cold,
+    /// A connection kept alive from an earlier request
+    Warm,
+    /// A new connection offering the tls session of an earlier one
+    Resumed,
+}
+
+impl ConnectionKind {
+    /// The name of the kind as it's serialized
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionKind::Cold => "cold",
+            ConnectionKind::Warm => "warm",
+            ConnectionKind::Resumed => "resumed",
+        }
+    }
+}
+
+/// The tls handshake with the target, the certificate is recorded even if it was rejected
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TlsInfo {
+    /// The protocol version, like `TLSv1_3`, unset if the handshake failed
+    pub version: Option<String>,
+    /// The cipher suite, unset if the handshake failed or over quic, which doesn't expose it
+    pub cipher_suite: Option<String>,
+    /// The application protocol agreed with alpn
+    pub alpn: Option<String>,
+    /// Whether a previous session was resumed rather than a full handshake done
+    pub resumed: bool,
+    /// Whether the target stapled an ocsp response to its certificate
+    pub ocsp_stapled: bool,
+    /// The target's leaf certificate, if it sent one that could be parsed
+    pub certificate: Option<CertificateInfo>,
+}
+
+/// The parts of a certificate that tell edge nodes apart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    /// The dns names and ip addresses the certificate is valid for
+    pub sans: Vec<String>,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+}
+
+/// The state of a job running a batch in the background
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// A batch run in the background, with the samples taken so far
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobResponse {
+    pub id: String,
+    pub status: JobStatus,
+    pub created: SystemTime,
+    /// When the job completed, failed or was cancelled
+    pub finished: Option<SystemTime>,
+    /// The number of samples the job takes
+    pub times: usize,
+    pub results: Vec<MeasureResponse>,
+    /// Why the job failed
+    pub error: Option<ErrorResponse>,
+}
+
+/// A scheduled measurement recorded by the service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// The name of the schedule that took the measurement
+    pub schedule: String,
+    pub target: String,
+    pub time: SystemTime,
+    pub measurement: Option<MeasureResponse>,
+    /// Why the measurement failed
+    pub error: Option<ErrorResponse>,
+}
+
+/// The response of the health endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub status: String,
+    pub uptime: Duration,
+}
+
+/// What a measure service is and where it runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceInfo {
+    /// The version of the measure crate
+    pub version: String,
+    /// The git commit the service was built from, if known
+    pub commit: Option<String>,
+    pub uptime: Duration,
+    /// The region label the service was configured with
+    pub region: Option<String>,
+    pub hostname: Option<String>,
+    /// The address the service reaches the internet from, if it could be looked up
+    pub public_ip: Option<String>,
+    /// The addresses of the service's outbound interfaces
+    pub private_ips: Vec<String>,
+    /// The kernel release
+    pub kernel: Option<String>,
+    pub cpu_model: Option<String>,
+    pub cpus: usize,
+    /// The measurement routes the service supports
+    pub measurements: Vec<String>,
+}
+
+/// The phases of a measured request, in the order they happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Dns,
+    TcpConnect,
+    TlsHandshake,
+    QuicHandshake,
+    Send,
+    FirstByte,
+    Body,
+}
+
+impl Phase {
+    /// The name of the phase as it's serialized
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Dns => "dns",
+            Phase::TcpConnect => "tcp_connect",
+            Phase::TlsHandshake => "tls_handshake",
+            Phase::QuicHandshake => "quic_handshake",
+            Phase::Send => "send",
+            Phase::FirstByte => "first_byte",
+            Phase::Body => "body",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Dns => "dns lookup",
+            Phase::TcpConnect => "tcp connect",
+            Phase::TlsHandshake => "tls handshake",
+            Phase::QuicHandshake => "quic handshake",
+            Phase::Send => "request send",
+            Phase::FirstByte => "first byte",
+            Phase::Body => "body",
+        })
+    }
+}
+
+/// The kind of failure, decides the status code the service responds with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The request to the service was invalid
+    BadRequest,
+    /// The request to the service had missing or invalid credentials
+    Unauthorized,
+    /// The target is refused by the service's target policy
+    TargetDenied,
+    /// The target host could not be resolved
+    Dns,
+    /// The connection to the target could not be established
+    Connect,
+    /// The tls handshake with the target failed
+    Tls,
+    /// The connection to the target failed after it was established
+    Io,
+    /// The target sent a response that isn't valid http
+    InvalidResponse,
+    /// The target responded with a non success status
+    TargetStatus,
+    /// The requested resource doesn't exist
+    NotFound,
+    /// The service is already doing as much as it allows
+    TooManyRequests,
+    /// The service itself failed
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::TargetDenied => StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// The json body of every error response from the service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// The phase of the request that failed, if the target was contacted
+    pub phase: Option<Phase>,
+    /// The timings collected before the failure, or all of them if the target status
+    /// was the failure
+    pub partial: Option<MeasureResponse>,
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum MeasureError {
-    #[error("TTFB error: {0}")]
-    Ttfb(#[from] TtfbError),
-    #[error("Blocking task spawn error: {0}")]
-    BlockingTaskSpawn(#[from] tokio::task::JoinError),
-    #[error("Reqwest error: {0}")]
-    Reqwest(#[from] reqwest::Error),
-    #[allow(dead_code)]
-    #[error("HTTP error: {0}")]
-    HttpError(reqwest::StatusCode),
+    #[error("HTTP error: {status}")]
+    HttpError {
+        status: http::StatusCode,
+        measurement: Box<MeasureResponse>,
+    },
     #[error("Bad request: {0}")]
     BadRequest(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Target denied: {0}")]
+    TargetDenied(String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid DNS name: {0}")]
+    InvalidDnsName(#[from] tokio_rustls::rustls::pki_types::InvalidDnsNameError),
+    #[error("Invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("Internal error: {0}")]
+    Internal(String),
+    #[error("{source} during {phase}")]
+    Phase {
+        phase: Phase,
+        partial: Box<MeasureResponse>,
+        source: Box<MeasureError>,
+    },
+}
+
+impl MeasureError {
+    /// Attach the phase the error happened in and the timings collected before it
+    pub fn during(self, phase: Phase, partial: &MeasureResponse) -> Self {
+        MeasureError::Phase {
+            phase,
+            partial: Box::new(partial.clone()),
+            source: Box::new(self),
+        }
+    }
+
+    /// The name of the variant, or of the underlying error for errors tagged with a phase
+    pub fn name(&self) -> &'static str {
+        match self {
+            MeasureError::HttpError { .. } => "http_error",
+            MeasureError::BadRequest(_) => "bad_request",
+            MeasureError::Unauthorized(_) => "unauthorized",
+            MeasureError::TargetDenied(_) => "target_denied",
+            MeasureError::Io(_) => "io",
+            MeasureError::InvalidDnsName(_) => "invalid_dns_name",
+            MeasureError::InvalidResponse(_) => "invalid_response",
+            MeasureError::Protocol(_) => "protocol",
+            MeasureError::NotFound(_) => "not_found",
+            MeasureError::TooManyRequests(_) => "too_many_requests",
+            MeasureError::Internal(_) => "internal",
+            MeasureError::Phase { source, .. } => source.name(),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            MeasureError::HttpError { .. } => ErrorKind::TargetStatus,
+            MeasureError::BadRequest(_) | MeasureError::InvalidDnsName(_) => ErrorKind::BadRequest,
+            MeasureError::Unauthorized(_) => ErrorKind::Unauthorized,
+            MeasureError::TargetDenied(_) => ErrorKind::TargetDenied,
+            MeasureError::Io(_) => ErrorKind::Io,
+            MeasureError::InvalidResponse(_) | MeasureError::Protocol(_) => {
+                ErrorKind::InvalidResponse
+            }
+            MeasureError::NotFound(_) => ErrorKind::NotFound,
+            MeasureError::TooManyRequests(_) => ErrorKind::TooManyRequests,
+            MeasureError::Internal(_) => ErrorKind::Internal,
+            MeasureError::Phase { phase, source, .. } => match (phase, source.kind()) {
+                (_, ErrorKind::BadRequest) => ErrorKind::BadRequest,
+                (Phase::Dns, _) => ErrorKind::Dns,
+                (Phase::TcpConnect | Phase::QuicHandshake, _) => ErrorKind::Connect,
+                (Phase::TlsHandshake, _) => ErrorKind::Tls,
+                (_, kind) => kind,
+            },
+        }
+    }
+}
+
+impl From<httparse::Error> for MeasureError {
+    fn from(e: httparse::Error) -> Self {
+        MeasureError::InvalidResponse(e.to_string())
+    }
 }
 
-impl From<TtfbOutcome> for MeasureResponse {
-    fn from(outcome: TtfbOutcome) -> Self {
-        MeasureResponse {
-            ip: outcome.ip_addr().to_string(),
-            dns_lookup_duration: outcome.dns_lookup_duration().map(|d| d.relative()),
-            tcp_connect_duration: outcome.tcp_connect_duration().relative(),
-            http_get_send_duration: outcome.http_get_send_duration().relative(),
-            ttfb_duration: outcome.ttfb_duration().relative(),
-            tls_handshake_duration: outcome.tls_handshake_duration().map(|d| d.relative()),
-            overall_duration: None,
+impl From<JsonRejection> for MeasureError {
+    fn from(rejection: JsonRejection) -> Self {
+        MeasureError::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for MeasureError {
+    fn from(rejection: QueryRejection) -> Self {
+        MeasureError::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<MeasureError> for ErrorResponse {
+    fn from(e: MeasureError) -> Self {
+        let (phase, partial) = match e {
+            MeasureError::Phase {
+                phase, ref partial, ..
+            } => (Some(phase), Some(partial.as_ref().clone())),
+            MeasureError::HttpError {
+                ref measurement, ..
+            } => (None, Some(measurement.as_ref().clone())),
+            _ => (None, None),
+        };
+
+        ErrorResponse {
+            kind: e.kind(),
+            message: e.to_string(),
+            phase,
+            partial,
         }
     }
 }
 
 impl IntoResponse for MeasureError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+        let body = ErrorResponse::from(self);
+
+        (body.kind.status_code(), Json(body)).into_response()
     }
 }
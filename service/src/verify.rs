@@ -0,0 +1,208 @@
+//! Checking that a response body is the content that was asked for
+//!
+//! Bodies are hashed with sha2-256 and compared to a given digest or the multihash of a cid,
+//! a fast answer with the wrong bytes shouldn't pass as a good measurement
+
+use sha2::{Digest, Sha256};
+use url::{Host, Url};
+
+use crate::{MeasureError, Verification, Verify};
+
+/// The multihash code of sha2-256
+const SHA2_256: u64 = 0x12;
+/// The multicodec of a raw block
+const RAW: u64 = 0x55;
+const DIGEST_LEN: usize = 32;
+
+/// The digest a body from the target is expected to have
+pub fn expected_digest(verify: &Verify, target: &Url) -> Result<Vec<u8>, MeasureError> {
+    match verify {
+        Verify::Sha256 { digest } => {
+            let digest = hex::decode(digest)
+                .map_err(|e| MeasureError::BadRequest(format!("Invalid sha256 digest: {}", e)))?;
+
+            if digest.len() != DIGEST_LEN {
+                return Err(MeasureError::BadRequest(format!(
+                    "Invalid sha256 digest: expected {} bytes, got {}",
+                    DIGEST_LEN,
+                    digest.len()
+                )));
+            }
+
+            Ok(digest)
+        }
+        Verify::Cid { cid: Some(cid) } => cid_digest(cid),
+        Verify::Cid { cid: None } => match target_cid(target) {
+            Some(cid) => cid_digest(&cid),
+            None => Err(MeasureError::BadRequest(
+                "The target has no cid to verify against".to_string(),
+            )),
+        },
+    }
+}
+
+/// Hash the body and compare it to the expected digest
+pub fn check(expected: &[u8], body: &[u8]) -> Verification {
+    let digest = Sha256::digest(body);
+
+    Verification {
+        verified: digest.as_slice() == expected,
+        expected: hex::encode(expected),
+        digest: hex::encode(digest),
+    }
+}
+
+/// The cid of a gateway url, from an `/ipfs/{cid}` path or a `{cid}.ipfs.` subdomain
+fn target_cid(target: &Url) -> Option<String> {
+    let mut segments = target.path_segments()?;
+
+    while let Some(segment) = segments.next() {
+        if segment == "ipfs" {
+            return segments
+                .next()
+                .filter(|cid| !cid.is_empty())
+                .map(str::to_string);
+        }
+    }
+
+    match target.host() {
+        Some(Host::Domain(domain)) => {
+            let mut labels = domain.split('.');
+            let cid = labels.next()?;
+
+            (labels.next() == Some("ipfs")).then(|| cid.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// The sha2-256 digest of a raw cid's multihash
+fn cid_digest(cid: &str) -> Result<Vec<u8>, MeasureError> {
+    let invalid =
+        |reason: String| MeasureError::BadRequest(format!("Invalid cid {}: {}", cid, reason));
+
+    // a v0 cid is a bare base58 multihash of a dag-pb node, a gateway answers it with the
+    // unixfs file and not the block, so the hash can't match
+    if cid.len() == 46 && cid.starts_with("Qm") {
+        return Err(invalid(
+            "v0 cids are dag-pb, only raw cids can be verified".to_string(),
+        ));
+    }
+
+    let (_, bytes) = multibase::decode(cid).map_err(|e| invalid(e.to_string()))?;
+    let (version, rest) = varint(&bytes).map_err(invalid)?;
+
+    if version != 1 {
+        return Err(invalid(format!("unsupported version {}", version)));
+    }
+
+    // only a raw block is served as it is, other codecs are decoded by the gateway
+    let (codec, multihash) = varint(rest).map_err(invalid)?;
+
+    if codec != RAW {
+        return Err(invalid(format!(
+            "only raw cids can be verified, the codec is 0x{:x}",
+            codec
+        )));
+    }
+
+    let (code, rest) = varint(multihash).map_err(invalid)?;
+    let (len, digest) = varint(rest).map_err(invalid)?;
+
+    if code != SHA2_256 {
+        return Err(invalid(format!(
+            "only sha2-256 hashes can be verified, the hash is 0x{:x}",
+            code
+        )));
+    }
+
+    if len as usize != DIGEST_LEN || digest.len() != DIGEST_LEN {
+        return Err(invalid("the digest is not 32 bytes".to_string()));
+    }
+
+    Ok(digest.to_vec())
+}
+
+fn varint(bytes: &[u8]) -> Result<(u64, &[u8]), String> {
+    unsigned_varint::decode::u64(bytes).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const RAW_CID: &str = "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e";
+
+    fn bad_request(result: Result<Vec<u8>, MeasureError>) -> bool {
+        matches!(result, Err(MeasureError::BadRequest(_)))
+    }
+
+    #[test]
+    fn cid_digest_decodes_raw_v1_cids() {
+        assert_eq!(cid_digest(RAW_CID).unwrap(), hex::decode(HELLO).unwrap());
+    }
+
+    #[test]
+    fn cid_digest_rejects_other_cids() {
+        // dag-pb v1
+        assert!(bad_request(cid_digest(
+            "bafybeifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e"
+        )));
+        // v0
+        assert!(bad_request(cid_digest(
+            "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4"
+        )));
+        // raw with a blake2b-256 hash
+        assert!(bad_request(cid_digest(
+            "bafk2bzacec4u2j5zsngt4cfffzjnpwt5vp5mjbhp4n5fhahoscepplhc57g6s"
+        )));
+        assert!(bad_request(cid_digest("not a cid")));
+    }
+
+    #[test]
+    fn target_cid_reads_paths_and_subdomains() {
+        let cid = |url: &str| target_cid(&Url::parse(url).unwrap());
+
+        assert_eq!(
+            cid(&format!("https://ipfs.io/ipfs/{}/file.txt", RAW_CID)).as_deref(),
+            Some(RAW_CID)
+        );
+        assert_eq!(
+            cid(&format!("https://{}.ipfs.dweb.link/", RAW_CID)).as_deref(),
+            Some(RAW_CID)
+        );
+        assert_eq!(cid("https://ipfs.io/ipfs/"), None);
+        assert_eq!(cid("https://example.com/file.txt"), None);
+        assert_eq!(cid("https://127.0.0.1/ipfs"), None);
+    }
+
+    #[test]
+    fn expected_digest_takes_the_target_cid() {
+        let target = Url::parse(&format!("https://ipfs.io/ipfs/{}", RAW_CID)).unwrap();
+        let other = Url::parse("https://example.com/").unwrap();
+
+        assert_eq!(
+            expected_digest(&Verify::Cid { cid: None }, &target).unwrap(),
+            hex::decode(HELLO).unwrap()
+        );
+        assert!(bad_request(expected_digest(
+            &Verify::Cid { cid: None },
+            &other
+        )));
+        assert!(bad_request(expected_digest(
+            &Verify::Sha256 {
+                digest: "abcd".to_string()
+            },
+            &other
+        )));
+    }
+
+    #[test]
+    fn check_compares_the_body_digest() {
+        let expected = hex::decode(HELLO).unwrap();
+
+        assert!(check(&expected, b"hello world").verified);
+        assert!(!check(&expected, b"hello world!").verified);
+    }
+}